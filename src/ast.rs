@@ -1,4 +1,8 @@
-#[derive(Clone, Debug, PartialEq)]
+use crate::span::Span;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Command {
     Mov(Mov),
     Add(Add),
@@ -8,10 +12,21 @@ pub enum Command {
     Pop(Pop),
     Call(Call),
     Allow(Allow),
-    Ret(Ret)
+    Ret(Ret),
+    Nop(Nop),
+    Jump(Jump),
+    Cmp(Cmp),
+    CondJump(CondJump),
+    Data(Data),
+}
+
+impl Command {
+    pub fn is_data(&self) -> bool {
+        matches!(self, Command::Data(_))
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum Register {
     R1 = 0x01,
@@ -32,6 +47,31 @@ pub enum Register {
     Rbp = 0x10,
 }
 
+impl Register {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let register = match name {
+            "r1" => Register::R1,
+            "r2" => Register::R2,
+            "r3" => Register::R3,
+            "r4" => Register::R4,
+            "r5" => Register::R5,
+            "r6" => Register::R6,
+            "r7" => Register::R7,
+            "r8" => Register::R8,
+            "r9" => Register::R9,
+            "r10" => Register::R10,
+            "r11" => Register::R11,
+            "r12" => Register::R12,
+            "r13" => Register::R13,
+            "r14" => Register::R14,
+            "r15" => Register::R15,
+            "rbp" => Register::Rbp,
+            _ => return None,
+        };
+        Some(register)
+    }
+}
+
 impl From<u8> for Register {
     fn from(value: u8) -> Self {
         match value {
@@ -55,61 +95,290 @@ impl From<u8> for Register {
         }
     }
 }
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Mov(pub MemoryFetching, pub Expr);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Add(pub MemoryFetching, pub Expr);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Sub(pub MemoryFetching, pub Expr);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Mul(pub MemoryFetching);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Div(pub MemoryFetching, pub Expr);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Pop(pub MemoryFetching);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Call(pub String);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Allow(pub Expr, pub Expr);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Ret;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Nop;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Jump(pub String);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cmp(pub MemoryFetching, pub Expr);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CondJump(pub Condition, pub String);
+
+/// A data directive. A label whose statements are all data directives points
+/// into the data segment instead of the code.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Data {
+    /// `.byte 1, 2, 3`: one byte per value.
+    Byte(Vec<Expr>),
+    /// `.word 1, $msg`: one little-endian 32-bit word per value.
+    Word(Vec<Expr>),
+    /// `.string "hi"`: the UTF-8 bytes of the string, then a `0` byte.
+    String(String),
+    /// `.zero 16`: that many `0` bytes.
+    Zero(Expr),
+}
+
+impl Data {
+    pub fn directive(&self) -> &'static str {
+        match self {
+            Data::Byte(_) => ".byte",
+            Data::Word(_) => ".word",
+            Data::String(_) => ".string",
+            Data::Zero(_) => ".zero",
+        }
+    }
+}
+
+/// Condition tested by a `CondJump` against the flags set by the last `cmp a, b`.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// `je`: `a == b`
+    Equal,
+    /// `jne`: `a != b`
+    NotEqual,
+    /// `jl`: `a < b`
+    Less,
+    /// `jg`: `a > b`
+    Greater,
+    /// `jle`: `a <= b`
+    LessEqual,
+    /// `jge`: `a >= b`
+    GreaterEqual,
+}
+
+impl Condition {
+    pub const ALL: [Condition; 6] = [
+        Condition::Equal,
+        Condition::NotEqual,
+        Condition::Less,
+        Condition::Greater,
+        Condition::LessEqual,
+        Condition::GreaterEqual,
+    ];
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Condition::Equal => "je",
+            Condition::NotEqual => "jne",
+            Condition::Less => "jl",
+            Condition::Greater => "jg",
+            Condition::LessEqual => "jle",
+            Condition::GreaterEqual => "jge",
+        }
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.mnemonic() == mnemonic)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Statement {
+    pub command: Command,
+    #[serde(default)]
+    pub span: Span,
+    /// For a statement expanded from a macro, the span of the outermost call;
+    /// `span` then points into the macro definition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub call_site: Option<Span>,
+    /// Span of each operand, in order. Empty for a statement that does not
+    /// come from source.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operands: Vec<Span>,
+}
+
+impl Statement {
+    pub fn new(command: Command, span: Span) -> Self {
+        Self {
+            command,
+            span,
+            call_site: None,
+            operands: vec![],
+        }
+    }
+
+    pub fn with_operands(mut self, operands: Vec<Span>) -> Self {
+        self.operands = operands;
+        self
+    }
+
+    /// Span of operand `index`, or of the whole statement if it is unknown.
+    pub fn operand_span(&self, index: usize) -> Span {
+        self.operands.get(index).copied().unwrap_or(self.span)
+    }
+
+    pub fn with_call_site(mut self, call_site: Option<Span>) -> Self {
+        self.call_site = call_site;
+        self
+    }
+}
+
+/// A statement that does not come from source, such as one built by hand or
+/// by the disassembler.
+impl From<Command> for Statement {
+    fn from(command: Command) -> Self {
+        Self::new(command, Span::default())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Label {
     pub name: String,
-    pub program: Vec<Command>,
+    pub program: Vec<Statement>,
+    /// Span of the label name where it is defined.
+    #[serde(default)]
+    pub span: Span,
 }
 
 impl Label {
-    pub fn new(name: String, program: Vec<Command>) -> Self {
-        Self { name, program }
+    pub fn new(name: String, program: Vec<Statement>) -> Self {
+        Self {
+            name,
+            program,
+            span: Span::default(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Whether the label points into the data segment: it holds at least one
+    /// statement, and only data directives.
+    pub fn is_data(&self) -> bool {
+        !self.program.is_empty() && self.program.iter().all(|x| x.command.is_data())
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// A named constant: `.equ NAME, value` or `.const NAME, value`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Constant {
+    pub name: String,
+    pub value: Expr,
+    /// Span of the constant name where it is defined.
+    #[serde(default)]
+    pub span: Span,
+}
+
+impl Constant {
+    pub fn new(name: String, value: Expr) -> Self {
+        Self {
+            name,
+            value,
+            span: Span::default(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Program {
     pub labels: Vec<Label>,
+    #[serde(default)]
+    pub constants: Vec<Constant>,
 }
 
 impl Program {
     pub fn new(labels: Vec<Label>) -> Self {
-        Self { labels }
+        Self {
+            labels,
+            constants: vec![],
+        }
+    }
+
+    pub fn with_constants(mut self, constants: Vec<Constant>) -> Self {
+        self.constants = constants;
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Loads a program written by `to_json`. Spans may be left out.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Expr {
     Int(i32),
     Label(String),
     Memory(MemoryFetching),
+    /// A constant defined by `.equ` or `.const`.
+    Const(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum UnaryOp {
+    Neg,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+        }
+    }
+
+    /// Binding strength; operators of the same precedence group to the left.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 1,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 2,
+        }
+    }
 }
 
 impl Expr {
@@ -135,8 +404,194 @@ impl Expr {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MemoryFetching {
     Addr(usize),
     Register(Register),
 }
+
+impl Display for Register {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Register::Rbp => write!(f, "rbp"),
+            reg => write!(f, "r{}", reg.clone() as u32),
+        }
+    }
+}
+
+impl Display for MemoryFetching {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryFetching::Addr(addr) => write!(f, "#{}", addr),
+            MemoryFetching::Register(reg) => write!(f, "{}", reg),
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Int(int) => write!(f, "{}", int),
+            Expr::Label(label) => write!(f, "${}", label),
+            Expr::Memory(memory) => write!(f, "{}", memory),
+            Expr::Const(name) => write!(f, "{}", name),
+            Expr::Unary(UnaryOp::Neg, expr) => match **expr {
+                Expr::Binary(..) => write!(f, "-({})", expr),
+                _ => write!(f, "-{}", expr),
+            },
+            Expr::Binary(op, left, right) => {
+                match **left {
+                    Expr::Binary(left_op, ..) if left_op.precedence() < op.precedence() => {
+                        write!(f, "({})", left)?
+                    }
+                    _ => write!(f, "{}", left)?,
+                }
+                write!(f, " {} ", op.symbol())?;
+                match **right {
+                    Expr::Binary(right_op, ..) if right_op.precedence() <= op.precedence() => {
+                        write!(f, "({})", right)
+                    }
+                    _ => write!(f, "{}", right),
+                }
+            }
+        }
+    }
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, ".equ {}, {}", self.name, self.value)
+    }
+}
+
+/// Writes `string` between double quotes, escaping what the lexer would not
+/// read back verbatim.
+pub fn quote_string(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\0' => quoted.push_str("\\0"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Display for Data {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.directive())?;
+        match self {
+            Data::Byte(values) | Data::Word(values) => {
+                let values = values.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", values.join(", "))
+            }
+            Data::String(string) => write!(f, "{}", quote_string(string)),
+            Data::Zero(count) => write!(f, "{}", count),
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Mov(Mov(mem, expr)) => write!(f, "mov {}, {}", mem, expr),
+            Command::Add(Add(mem, expr)) => write!(f, "add {}, {}", mem, expr),
+            Command::Sub(Sub(mem, expr)) => write!(f, "sub {}, {}", mem, expr),
+            Command::Mul(Mul(mem)) => write!(f, "mul {}", mem),
+            Command::Div(Div(mem, expr)) => write!(f, "div {}, {}", mem, expr),
+            Command::Pop(Pop(mem)) => write!(f, "pop {}", mem),
+            Command::Call(Call(label)) => write!(f, "call ${}", label),
+            Command::Allow(Allow(to, from)) => write!(f, "allow {} {}", to, from),
+            Command::Ret(_) => write!(f, "ret"),
+            Command::Nop(_) => write!(f, "nop"),
+            Command::Jump(Jump(label)) => write!(f, "jmp ${}", label),
+            Command::Cmp(Cmp(mem, expr)) => write!(f, "cmp {}, {}", mem, expr),
+            Command::CondJump(CondJump(cond, label)) => {
+                write!(f, "{} ${}", cond.mnemonic(), label)
+            }
+            Command::Data(data) => write!(f, "{}", data),
+        }
+    }
+}
+
+impl Display for Statement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.command)
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}:", self.name)?;
+        for statement in &self.program {
+            writeln!(f, "    {}", statement)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for constant in &self.constants {
+            writeln!(f, "{}", constant)?;
+        }
+        for (i, label) in self.labels.iter().enumerate() {
+            if i != 0 || !self.constants.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{}", label)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let program = Program::new(vec![Label::new(
+            "main".to_string(),
+            vec![
+                Statement::new(
+                    Command::Mov(Mov(
+                        MemoryFetching::Register(Register::Rbp),
+                        Expr::Memory(MemoryFetching::Addr(4)),
+                    )),
+                    Span::new(10, 22),
+                ),
+                Statement::new(
+                    Command::CondJump(CondJump(Condition::Less, "main".to_string())),
+                    Span::new(27, 35),
+                ),
+                Command::Ret(Ret).into(),
+            ],
+        )
+        .with_span(Span::new(0, 4))]);
+
+        let json = program.to_json();
+        assert!(json.contains("\"start\": 10"));
+        assert_eq!(Program::from_json(&json).unwrap(), program);
+    }
+
+    #[test]
+    fn test_json_without_spans() {
+        let json = r#"{"labels": [{"name": "main", "program": [
+            {"command": {"Add": [{"Register": "R1"}, {"Int": 2}]}},
+            {"command": {"Call": "main"}}
+        ]}]}"#;
+        let program = Program::from_json(json).unwrap();
+        assert_eq!(
+            program.to_string(),
+            "main:\n    add r1, 2\n    call $main\n"
+        );
+        assert_eq!(program.labels[0].span, Span::default());
+        assert!(Program::from_json(r#"{"labels": [{"name": 1}]}"#).is_err());
+    }
+}
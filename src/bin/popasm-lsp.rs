@@ -0,0 +1,32 @@
+//! `popasm-lsp [-I <dir>]...`: the PopperASM language server, over stdio.
+//! See `popper_asm::lsp`.
+
+use popper_asm::lsp::Server;
+use popper_asm::AssemblerOptions;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut options = AssemblerOptions::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("-I", Some(dir)) => options = options.with_include_path(PathBuf::from(dir)),
+            _ => {
+                eprintln!("Usage: popasm-lsp [-I <dir>]...");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let mut server = Server::new(options);
+    let result = server.run(&mut std::io::stdin().lock(), &mut std::io::stdout().lock());
+    match result {
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+        Ok(()) if server.is_shut_down() => ExitCode::SUCCESS,
+        Ok(()) => ExitCode::FAILURE,
+    }
+}
@@ -0,0 +1,553 @@
+//! Diagnostics and their rendering as text.
+//!
+//! A [`Diagnostic`] has a message, labelled spans and notes. [`Renderer`]
+//! prints it with the `file:line:col` of its first label and the lines
+//! around every label, for example:
+//!
+//! ```text
+//! error[E0018]: Unknown label `nowhere`
+//!  --> lib/macros.popasm:2:13
+//!   |
+//! 1 | .macro load
+//! 2 |     mov r1, $nowhere
+//!   |             ^^^^^^^^
+//! 3 | .endm
+//!   |
+//!  ::: main.popasm:5:5
+//!   |
+//! 5 |     load
+//!   |     ---- in the macro called here
+//!   |
+//! ```
+//!
+//! [`Diagnostic::to_json`] gives the same information in a form meant for
+//! editors and CI tools.
+
+use crate::parser::Error;
+use crate::source_map::{SourceFile, SourceMap};
+use crate::span::{FileId, Span};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A span to underline, with an optional message printed after it.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    /// The primary label is where the problem is, marked with `^`; secondary
+    /// labels give context and are marked with `-`.
+    pub primary: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable code of the kind of problem, such as `E0001`.
+    pub code: Option<String>,
+    pub message: String,
+    /// The primary label first, then the secondary ones.
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String) -> Self {
+        Self {
+            severity,
+            code: None,
+            message,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: String) -> Self {
+        Self::new(Severity::Warning, message)
+    }
+
+    pub fn with_code(mut self, code: String) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: String) -> Self {
+        self.labels.push(Label {
+            span,
+            message,
+            primary: true,
+        });
+        self
+    }
+
+    pub fn with_secondary_label(mut self, span: Span, message: String) -> Self {
+        self.labels.push(Label {
+            span,
+            message,
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    /// Span of the primary label, or of the first label if none is primary.
+    pub fn span(&self) -> Option<Span> {
+        self.primary().map(|i| self.labels[i].span)
+    }
+
+    fn primary(&self) -> Option<usize> {
+        self.labels
+            .iter()
+            .position(|x| x.primary)
+            .or((!self.labels.is_empty()).then_some(0))
+    }
+
+    /// The diagnostic as one line of JSON, with the file, byte span and
+    /// line/column range of the primary label at the top level:
+    ///
+    /// ```text
+    /// {"severity":"error","code":null,"message":"Unknown label `x`",
+    ///  "file":"main.popasm","span":{"start":10,"end":12},
+    ///  "range":{"start":{"line":2,"column":5},"end":{"line":2,"column":7}},
+    ///  "labels":[],"notes":[]}
+    /// ```
+    ///
+    /// `labels` holds the other labels, each with a `message` and the same
+    /// location fields. The location fields are left out for a span outside
+    /// `sources`. Lines and columns start at 1, and the end of a range
+    /// is just after the span.
+    pub fn to_json(&self, sources: &SourceMap) -> String {
+        let primary = self.primary();
+        let json = JsonDiagnostic {
+            severity: self.severity,
+            code: self.code.as_deref(),
+            message: &self.message,
+            location: primary.and_then(|i| JsonLocation::new(self.labels[i].span, sources)),
+            labels: self
+                .labels
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| Some(*i) != primary)
+                .map(|(_, x)| JsonLabel {
+                    message: &x.message,
+                    location: JsonLocation::new(x.span, sources),
+                })
+                .collect(),
+            notes: &self.notes,
+        };
+        serde_json::to_string(&json).unwrap()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    severity: Severity,
+    code: Option<&'a str>,
+    message: &'a str,
+    #[serde(flatten)]
+    location: Option<JsonLocation>,
+    labels: Vec<JsonLabel<'a>>,
+    notes: &'a [String],
+}
+
+#[derive(Serialize)]
+struct JsonLabel<'a> {
+    message: &'a str,
+    #[serde(flatten)]
+    location: Option<JsonLocation>,
+}
+
+#[derive(Serialize)]
+struct JsonLocation {
+    file: String,
+    span: JsonSpan,
+    range: JsonRange,
+}
+
+impl JsonLocation {
+    /// The location of `span`, or `None` if it is outside `sources`.
+    fn new(span: Span, sources: &SourceMap) -> Option<Self> {
+        let file = sources.file_of(span)?;
+        let position = |offset| {
+            let (line, column) = file.line_col(offset);
+            JsonPosition { line, column }
+        };
+        Some(Self {
+            file: file.path.display().to_string(),
+            span: JsonSpan {
+                start: span.start(),
+                end: span.end(),
+            },
+            range: JsonRange {
+                start: position(span.start()),
+                end: position(span.end()),
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct JsonSpan {
+    start: usize,
+    end: usize,
+}
+
+#[derive(Serialize)]
+struct JsonRange {
+    start: JsonPosition,
+    end: JsonPosition,
+}
+
+#[derive(Serialize)]
+struct JsonPosition {
+    line: usize,
+    column: usize,
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(error: &Error) -> Self {
+        let diagnostic = Diagnostic::error(error.message.clone())
+            .with_code(error.code.code())
+            .with_label(error.span, String::new());
+        match error.call_site {
+            Some(call_site) => {
+                diagnostic.with_secondary_label(call_site, "in the macro called here".to_string())
+            }
+            None => diagnostic,
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Turns diagnostics into text, with ANSI colours if asked to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Renderer {
+    pub color: bool,
+    /// Number of lines shown before and after each label.
+    pub context: usize,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self {
+            color: false,
+            context: 1,
+        }
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_context(mut self, context: usize) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic, sources: &SourceMap) -> String {
+        let severity_style = match diagnostic.severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        };
+        let severity = match &diagnostic.code {
+            Some(code) => format!("{}[{}]", diagnostic.severity, code),
+            None => diagnostic.severity.to_string(),
+        };
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(severity_style, &severity),
+            self.paint(BOLD, &format!(": {}", diagnostic.message)),
+        );
+
+        // labels grouped by file, in the order the files first appear;
+        // labels outside the source map are left without a snippet
+        let mut files: Vec<(FileId, Vec<&Label>)> = vec![];
+        for label in &diagnostic.labels {
+            if sources.file_of(label.span).is_none() {
+                continue;
+            }
+            match files
+                .iter_mut()
+                .find(|(file, _)| *file == label.span.file())
+            {
+                Some((_, labels)) => labels.push(label),
+                None => files.push((label.span.file(), vec![label])),
+            }
+        }
+        let width = files
+            .iter()
+            .flat_map(|(file, labels)| {
+                labels
+                    .iter()
+                    .map(|x| self.lines(&sources.files()[*file], x.span).1)
+            })
+            .max()
+            .map_or(1, |x| (x + self.context).to_string().len());
+        let gutter = self.paint(BLUE, &format!("{} |", " ".repeat(width)));
+
+        for (i, (file, labels)) in files.iter().enumerate() {
+            let arrow = if i == 0 { "-->" } else { ":::" };
+            out += &format!(
+                "{}{} {}\n",
+                " ".repeat(width),
+                self.paint(BLUE, arrow),
+                sources.location(labels[0].span).unwrap_or_default()
+            );
+            out += &format!("{}\n", gutter);
+            let source = &sources.files()[*file];
+            self.render_file(&mut out, source, labels, width, severity_style);
+            out += &format!("{}\n", gutter);
+        }
+
+        for note in &diagnostic.notes {
+            out += &format!(
+                "{} {} {} {}\n",
+                " ".repeat(width),
+                self.paint(BLUE, "="),
+                self.paint(BOLD, "note:"),
+                note
+            );
+        }
+        out
+    }
+
+    /// The lines of `file` covered by `labels` and their context, each
+    /// followed by the markers of the labels on it.
+    fn render_file(
+        &self,
+        out: &mut String,
+        file: &SourceFile,
+        labels: &[&Label],
+        width: usize,
+        severity_style: &str,
+    ) {
+        let mut shown = labels
+            .iter()
+            .flat_map(|label| {
+                let (first, last) = self.lines(file, label.span);
+                first.saturating_sub(self.context).max(1)
+                    ..=(last + self.context).min(file.line_count()).max(last)
+            })
+            .collect::<Vec<_>>();
+        shown.sort_unstable();
+        shown.dedup();
+
+        let mut previous = None;
+        for line in shown {
+            if previous.is_some_and(|x| x + 1 < line) {
+                *out += &format!("{}\n", self.paint(BLUE, "..."));
+            }
+            previous = Some(line);
+
+            let number = self.paint(BLUE, &format!("{:>width$} |", line));
+            match file.line(line) {
+                "" => *out += &format!("{}\n", number),
+                text => *out += &format!("{} {}\n", number, text),
+            }
+
+            for label in labels {
+                let (first, last) = self.lines(file, label.span);
+                if !(first..=last).contains(&line) {
+                    continue;
+                }
+                let range = file.line_range(line);
+                let start = label.span.start().clamp(range.start, range.end);
+                let end = label.span.end().clamp(range.start, range.end);
+
+                let prefix = file.source[range.start..start]
+                    .chars()
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect::<String>();
+                let (mark, style) = if label.primary {
+                    ("^", severity_style)
+                } else {
+                    ("-", BLUE)
+                };
+                let marks = mark.repeat(file.source[start..end].chars().count().max(1));
+                let message = match line == last && !label.message.is_empty() {
+                    true => format!(" {}", label.message),
+                    false => String::new(),
+                };
+                *out += &format!(
+                    "{} {}{}\n",
+                    self.paint(BLUE, &format!("{} |", " ".repeat(width))),
+                    prefix,
+                    self.paint(style, &format!("{}{}", marks, message))
+                );
+            }
+        }
+    }
+
+    /// First and last line covered by `span`. A span ending just after a
+    /// newline does not cover the next line.
+    fn lines(&self, file: &SourceFile, span: Span) -> (usize, usize) {
+        let (first, _) = file.line_col(span.start());
+        let (last, _) = file.line_col(span.end().saturating_sub(1).max(span.start()));
+        (first, last)
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_render() {
+        let main = "main:\n    mov r1, 1\n    load\n    ret\n";
+        let lib = ".macro load\n    mov r1, $nowhere\n.endm\n";
+        let mut sources = SourceMap::new().with_file(Path::new("main.popasm"), main.to_string());
+        let lib = sources.add("lib.popasm".into(), lib.to_string());
+
+        let diagnostic = Diagnostic::error("Unknown label `nowhere`".to_string())
+            .with_label(Span::new(24, 32).with_file(lib), String::new())
+            .with_secondary_label(Span::new(24, 28), "in the macro called here".to_string())
+            .with_note("labels are defined with `name:`".to_string());
+        assert_eq!(
+            Renderer::new().render(&diagnostic, &sources),
+            "\
+error: Unknown label `nowhere`
+ --> lib.popasm:2:13
+  |
+1 | .macro load
+2 |     mov r1, $nowhere
+  |             ^^^^^^^^
+3 | .endm
+  |
+ ::: main.popasm:3:5
+  |
+2 |     mov r1, 1
+3 |     load
+  |     ---- in the macro called here
+4 |     ret
+  |
+  = note: labels are defined with `name:`
+"
+        );
+
+        let diagnostic = Diagnostic::warning("Unreachable code".to_string())
+            .with_label(Span::new(6, 28), "never runs".to_string());
+        assert_eq!(
+            Renderer::new()
+                .with_context(0)
+                .render(&diagnostic, &sources),
+            "\
+warning: Unreachable code
+ --> main.popasm:2:1
+  |
+2 |     mov r1, 1
+  | ^^^^^^^^^^^^^
+3 |     load
+  | ^^^^^^^^ never runs
+  |
+"
+        );
+
+        let diagnostic = diagnostic.with_code("E0001".to_string());
+        let rendered = Renderer::new().render(&diagnostic, &sources);
+        assert!(rendered.starts_with("warning[E0001]: Unreachable code\n"));
+
+        let colored = Renderer::new()
+            .with_color(true)
+            .render(&diagnostic, &sources);
+        assert!(colored
+            .starts_with("\x1b[1;33mwarning[E0001]\x1b[0m\x1b[1m: Unreachable code\x1b[0m\n"));
+    }
+
+    #[test]
+    fn test_json() {
+        let source = "main:\n    mov r1, $nowhere\n";
+        let sources = SourceMap::new().with_file(Path::new("main.popasm"), source.to_string());
+        let diagnostic = Diagnostic::error("Unknown label `nowhere`".to_string())
+            .with_code("E0001".to_string())
+            .with_label(Span::new(14, 22), String::new())
+            .with_secondary_label(Span::new(0, 4), "in this label".to_string())
+            .with_note("labels are defined with `name:`".to_string());
+
+        let json: serde_json::Value = serde_json::from_str(&diagnostic.to_json(&sources)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "severity": "error",
+                "code": "E0001",
+                "message": "Unknown label `nowhere`",
+                "file": "main.popasm",
+                "span": {"start": 14, "end": 22},
+                "range": {
+                    "start": {"line": 2, "column": 9},
+                    "end": {"line": 2, "column": 17}
+                },
+                "labels": [{
+                    "message": "in this label",
+                    "file": "main.popasm",
+                    "span": {"start": 0, "end": 4},
+                    "range": {
+                        "start": {"line": 1, "column": 1},
+                        "end": {"line": 1, "column": 5}
+                    }
+                }],
+                "notes": ["labels are defined with `name:`"]
+            })
+        );
+        assert!(!diagnostic.to_json(&sources).contains('\n'));
+    }
+
+    #[test]
+    fn test_outside_sources() {
+        let sources = SourceMap::new().with_file(Path::new("main.popasm"), "main:\n".to_string());
+        let diagnostic = Diagnostic::error("Unknown label `x`".to_string())
+            .with_label(Span::new(40, 41).with_file(3), String::new())
+            .with_note("from a JSON program".to_string());
+
+        assert_eq!(
+            Renderer::new().render(&diagnostic, &sources),
+            "error: Unknown label `x`\n  = note: from a JSON program\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&diagnostic.to_json(&sources)).unwrap();
+        assert_eq!(json.get("file"), None);
+        assert_eq!(json["message"], "Unknown label `x`");
+    }
+}
@@ -0,0 +1,343 @@
+use crate::ast::*;
+use crate::machine_code::*;
+use std::collections::BTreeSet;
+
+pub fn label_name(addr: u32) -> String {
+    format!("label_{}", addr)
+}
+
+/// Number of bytes per `.byte` line for the data segment.
+const BYTES_PER_LINE: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct Disassembler {
+    pub machine_code: MachineCode,
+    labels: BTreeSet<u32>,
+}
+
+impl Disassembler {
+    pub fn new(machine_code: MachineCode) -> Self {
+        Self {
+            machine_code,
+            labels: BTreeSet::new(),
+        }
+    }
+
+    pub fn disassemble(&mut self) -> Result<Program, DecodeError> {
+        let mut instrs = self.machine_code.instructions().to_vec();
+        if instrs.last() == Some(&MachineCodeInstruction::end_marker()) {
+            instrs.pop();
+        }
+
+        self.setup_labels(&instrs)?;
+
+        let mut labels: Vec<Label> = vec![];
+        for (i, instr) in instrs.iter().enumerate() {
+            let addr = i as u32 + 1;
+            if labels.is_empty() || self.labels.contains(&addr) {
+                labels.push(Label::new(label_name(addr), vec![]));
+            }
+            let command = self.instruction_to_command(instr, i * INSTRUCTION_SIZE)?;
+            labels.last_mut().unwrap().program.push(command.into());
+        }
+
+        let end = instrs.len() as u32 + 1;
+        if self.labels.contains(&end) {
+            labels.push(Label::new(label_name(end), vec![]));
+        }
+
+        labels.extend(self.data_labels());
+        Ok(Program::new(labels))
+    }
+
+    /// The data segment as `.byte` directives, split at every address a
+    /// label operand points to.
+    fn data_labels(&self) -> Vec<Label> {
+        let data = self.machine_code.data();
+        if data.is_empty() {
+            return vec![];
+        }
+
+        let mut starts = self
+            .labels
+            .range(DATA_START..)
+            .map(|x| (x - DATA_START) as usize)
+            .filter(|x| *x < data.len())
+            .collect::<Vec<_>>();
+        if starts.first() != Some(&0) {
+            starts.insert(0, 0);
+        }
+        let ends = starts.iter().skip(1).copied().chain([data.len()]);
+
+        starts
+            .iter()
+            .zip(ends)
+            .map(|(start, end)| {
+                let program = data[*start..end]
+                    .chunks(BYTES_PER_LINE)
+                    .map(|chunk| {
+                        let bytes = chunk.iter().map(|x| Expr::Int(*x as i32)).collect();
+                        Command::Data(Data::Byte(bytes)).into()
+                    })
+                    .collect();
+                Label::new(label_name(DATA_START + *start as u32), program)
+            })
+            .collect()
+    }
+
+    fn is_data_addr(&self, addr: u32) -> bool {
+        (DATA_START..DATA_START + self.machine_code.data().len() as u32).contains(&addr)
+    }
+
+    fn setup_labels(&mut self, instrs: &[MachineCodeInstruction]) -> Result<(), DecodeError> {
+        let end = instrs.len() as u32 + 1;
+        for (i, instr) in instrs.iter().enumerate() {
+            let offset = i * INSTRUCTION_SIZE;
+            let label = u32::from_le_bytes(instr.label);
+            if label == 0 || label > end {
+                return Err(DecodeError::new(
+                    format!("Label address {} is out of range", label),
+                    offset,
+                ));
+            }
+            self.labels.insert(label);
+
+            for (operand_type, operand, type_offset) in [
+                (instr.operand1_type, instr.operand1, 5),
+                (instr.operand2_type, instr.operand2, 10),
+            ] {
+                if operand_type != LABEL {
+                    continue;
+                }
+                let addr = u32::from_le_bytes(operand);
+                if (addr == 0 || addr > end) && !self.is_data_addr(addr) {
+                    return Err(DecodeError::new(
+                        format!("Label address {} is out of range", addr),
+                        offset + type_offset + 1,
+                    ));
+                }
+                self.labels.insert(addr);
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction_to_command(
+        &self,
+        instr: &MachineCodeInstruction,
+        offset: usize,
+    ) -> Result<Command, DecodeError> {
+        let op1 = (instr.operand1_type, instr.operand1, offset + 5);
+        let op2 = (instr.operand2_type, instr.operand2, offset + 10);
+
+        let command = match instr.instr {
+            MOV => Command::Mov(Mov(self.memory(op1)?, self.expr(op2)?)),
+            ADD => Command::Add(Add(self.memory(op1)?, self.expr(op2)?)),
+            SUB => Command::Sub(Sub(self.memory(op1)?, self.expr(op2)?)),
+            MUL => {
+                self.void(op2)?;
+                Command::Mul(Mul(self.memory(op1)?))
+            }
+            DIV => Command::Div(Div(self.memory(op1)?, self.expr(op2)?)),
+            POP => {
+                self.void(op2)?;
+                Command::Pop(Pop(self.memory(op1)?))
+            }
+            CALL => {
+                self.void(op2)?;
+                Command::Call(Call(self.label(op1)?))
+            }
+            ALLOW => Command::Allow(Allow(self.expr(op1)?, self.expr(op2)?)),
+            RET => {
+                self.void(op1)?;
+                self.void(op2)?;
+                Command::Ret(Ret)
+            }
+            NOP => {
+                self.void(op1)?;
+                self.void(op2)?;
+                Command::Nop(Nop)
+            }
+            JUMP => {
+                self.void(op2)?;
+                Command::Jump(Jump(self.label(op1)?))
+            }
+            CMP => Command::Cmp(Cmp(self.memory(op1)?, self.expr(op2)?)),
+            e => match opcode_condition(e) {
+                Some(cond) => {
+                    self.void(op2)?;
+                    Command::CondJump(CondJump(cond, self.label(op1)?))
+                }
+                None => {
+                    return Err(DecodeError::new(
+                        format!("Unknown opcode {:#04x}", e),
+                        offset + 4,
+                    ))
+                }
+            },
+        };
+
+        Ok(command)
+    }
+
+    fn void(&self, (operand_type, _, offset): (u8, [u8; 4], usize)) -> Result<(), DecodeError> {
+        match operand_type {
+            VOID => Ok(()),
+            e => Err(DecodeError::new(
+                format!("Expected no operand, found operand type {:#04x}", e),
+                offset,
+            )),
+        }
+    }
+
+    fn label(
+        &self,
+        (operand_type, operand, offset): (u8, [u8; 4], usize),
+    ) -> Result<String, DecodeError> {
+        match operand_type {
+            LABEL => Ok(label_name(u32::from_le_bytes(operand))),
+            e => Err(DecodeError::new(
+                format!("Expected label operand, found operand type {:#04x}", e),
+                offset,
+            )),
+        }
+    }
+
+    fn memory(
+        &self,
+        (operand_type, operand, offset): (u8, [u8; 4], usize),
+    ) -> Result<MemoryFetching, DecodeError> {
+        let value = u32::from_le_bytes(operand);
+        match operand_type {
+            REG => match value {
+                0x01..=0x10 => Ok(MemoryFetching::Register(Register::from(value as u8))),
+                e => Err(DecodeError::new(
+                    format!("Invalid register {:#04x}", e),
+                    offset + 1,
+                )),
+            },
+            MEM => Ok(MemoryFetching::Addr(value as usize)),
+            e => Err(DecodeError::new(
+                format!(
+                    "Expected register or memory operand, found operand type {:#04x}",
+                    e
+                ),
+                offset,
+            )),
+        }
+    }
+
+    fn expr(&self, operand: (u8, [u8; 4], usize)) -> Result<Expr, DecodeError> {
+        match operand.0 {
+            INT => Ok(Expr::Int(i32::from_le_bytes(operand.1))),
+            LABEL => Ok(Expr::Label(self.label(operand)?)),
+            REG | MEM => Ok(Expr::Memory(self.memory(operand)?)),
+            e => Err(DecodeError::new(
+                format!("Expected operand, found operand type {:#04x}", e),
+                operand.2,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn assemble(source: &str) -> MachineCode {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = Parser::new(lexer.get_tokens()).parse().unwrap();
+        MachineCodeCompiler::new(program).compile().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = r#"
+        main:
+            mov r1, 7
+            mov #4, r1
+            add r1, r2
+            sub r3, #8
+            mul r4
+            div rbp, 2
+            pop r15
+            call $foo
+            allow 1 $foo
+            jmp $foo
+            cmp r1, 3
+            je $main
+            jne $foo
+            jl $main
+            jg $foo
+            jle $main
+            jge $foo
+        foo:
+            mov r2, $main
+            nop
+            ret
+        "#;
+        let bytecode = assemble(source).to_bytecode();
+
+        let machine_code = MachineCode::from_bytecode(&bytecode).unwrap();
+        let program = Disassembler::new(machine_code).disassemble().unwrap();
+        let text = program.to_string();
+
+        assert!(text.starts_with("label_1:\n    mov r1, 7\n    mov #4, r1\n"));
+        assert!(text.contains("\nlabel_18:\n    mov r2, $label_1\n    nop\n    ret\n"));
+        assert_eq!(assemble(&text).to_bytecode(), bytecode);
+    }
+
+    #[test]
+    fn test_data_round_trip() {
+        let source = r#"
+        main:
+            mov r1, $msg
+            mov r2, $table
+        msg:
+            .string "hello, world!"
+        table:
+            .word $main, $table, 1000000
+            .zero 3
+        "#;
+        let bytecode = assemble(source).to_bytecode();
+
+        let machine_code = MachineCode::from_bytecode(&bytecode).unwrap();
+        let program = Disassembler::new(machine_code).disassemble().unwrap();
+        let text = program.to_string();
+
+        assert!(text.contains("\nlabel_32768:\n    .byte 104, 101, 108, 108,"));
+        assert!(text.contains("\nlabel_32782:\n    .byte 1, 0, 0, 0, 14, 128,"));
+        assert_eq!(assemble(&text).to_bytecode(), bytecode);
+    }
+
+    #[test]
+    fn test_label_out_of_range() {
+        let machine_code = MachineCode::new(vec![MachineCodeInstruction::new(
+            [0x1, 0x0, 0x0, 0x0],
+            CALL,
+            LABEL,
+            [0x9, 0x0, 0x0, 0x0],
+            VOID,
+            Default::default(),
+        )]);
+        let err = Disassembler::new(machine_code).disassemble().unwrap_err();
+        assert_eq!(err.offset, 6);
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let machine_code = MachineCode::new(vec![MachineCodeInstruction::new(
+            [0x1, 0x0, 0x0, 0x0],
+            POP,
+            REG,
+            [0x20, 0x0, 0x0, 0x0],
+            VOID,
+            Default::default(),
+        )]);
+        let err = Disassembler::new(machine_code).disassemble().unwrap_err();
+        assert_eq!(err.offset, 6);
+    }
+}
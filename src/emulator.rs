@@ -0,0 +1,595 @@
+//! Emulator for the PopperCPU.
+//!
+//! Instructions are addressed the same way `MachineCodeCompiler::setup_labels`
+//! assigns label addresses: the first instruction lives at address 1. Execution
+//! starts there and stops when the program counter leaves the code, or on a
+//! `ret` with an empty call stack.
+//!
+//! - `mov`, `add`, `sub` and `div` store `op1 <op> op2` into their first operand.
+//! - `mul` multiplies `r1` by its operand, storing the result in `r1`.
+//! - `pop` moves the top of the value stack (see [`Emulator::push`]) into its operand.
+//! - `allow a b` makes the `b` bytes starting at address `a` addressable, up
+//!   to [`MAX_MEMORY_SIZE`] bytes of memory in all.
+//! - `call` pushes the return address on the call stack, `ret` pops it.
+//! - `cmp a, b` sets the [`Flags`] from `a - b`; `je`, `jne`, `jl`, `jg`, `jle`
+//!   and `jge` jump when the flags match their condition. No other instruction
+//!   touches the flags.
+//! - `#addr` operands read and write little-endian 32-bit words in memory.
+//!
+//! The data segment is copied into memory at `DATA_START` before the first
+//! instruction runs.
+
+use crate::ast::{Condition, Register};
+use crate::machine_code::*;
+
+pub const DEFAULT_MEMORY_SIZE: usize = 0x10000;
+
+/// Largest memory `allow` can grow to, in bytes.
+pub const MAX_MEMORY_SIZE: usize = 0x100_0000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmulatorError {
+    pub message: String,
+    pub addr: u32,
+}
+
+impl EmulatorError {
+    pub fn new(message: String, addr: u32) -> Self {
+        Self { message, addr }
+    }
+}
+
+impl std::fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at instruction {}: {}", self.addr, self.message)
+    }
+}
+
+/// Result of the last `cmp a, b`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Flags {
+    /// `a == b`
+    pub zero: bool,
+    /// `a < b`, as signed integers.
+    pub negative: bool,
+}
+
+impl Flags {
+    pub fn compare(a: i32, b: i32) -> Self {
+        Self {
+            zero: a == b,
+            negative: a < b,
+        }
+    }
+
+    pub fn holds(&self, cond: Condition) -> bool {
+        match cond {
+            Condition::Equal => self.zero,
+            Condition::NotEqual => !self.zero,
+            Condition::Less => self.negative,
+            Condition::Greater => !self.zero && !self.negative,
+            Condition::LessEqual => self.zero || self.negative,
+            Condition::GreaterEqual => !self.negative,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Emulator {
+    pub registers: [i32; 16],
+    pub memory: Vec<u8>,
+    pub call_stack: Vec<u32>,
+    pub stack: Vec<i32>,
+    pub flags: Flags,
+    pub pc: u32,
+    pub steps: usize,
+    code: Vec<MachineCodeInstruction>,
+    halted: bool,
+}
+
+impl Emulator {
+    pub fn new(machine_code: MachineCode) -> Self {
+        let data_start = DATA_START as usize;
+        let data = machine_code.data();
+        let mut memory = vec![0; DEFAULT_MEMORY_SIZE.max(data_start + data.len())];
+        memory[data_start..data_start + data.len()].copy_from_slice(data);
+
+        Self {
+            registers: [0; 16],
+            memory,
+            call_stack: vec![],
+            stack: vec![],
+            flags: Flags::default(),
+            pc: 1,
+            steps: 0,
+            code: machine_code.instructions().to_vec(),
+            halted: false,
+        }
+    }
+
+    pub fn register(&self, reg: Register) -> i32 {
+        self.registers[reg as usize - 1]
+    }
+
+    pub fn set_register(&mut self, reg: Register, value: i32) {
+        self.registers[reg as usize - 1] = value;
+    }
+
+    /// Pushes a value that a later `pop` will receive.
+    pub fn push(&mut self, value: i32) {
+        self.stack.push(value);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn read_memory(&self, addr: usize) -> Result<i32, EmulatorError> {
+        let bytes = self.memory.get(addr..addr + 4).ok_or_else(|| {
+            EmulatorError::new(format!("Memory read out of bounds at #{}", addr), self.pc)
+        })?;
+        let mut word: [u8; 4] = Default::default();
+        word.copy_from_slice(bytes);
+        Ok(i32::from_le_bytes(word))
+    }
+
+    pub fn write_memory(&mut self, addr: usize, value: i32) -> Result<(), EmulatorError> {
+        let pc = self.pc;
+        let bytes = self.memory.get_mut(addr..addr + 4).ok_or_else(|| {
+            EmulatorError::new(format!("Memory write out of bounds at #{}", addr), pc)
+        })?;
+        bytes.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), EmulatorError> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Like [`Emulator::run`], but fails once more than `max_steps` instructions have run.
+    pub fn run_with_limit(&mut self, max_steps: usize) -> Result<(), EmulatorError> {
+        while self.step()? {
+            if self.steps >= max_steps && !self.halted {
+                return Err(EmulatorError::new(
+                    format!("Step limit of {} reached", max_steps),
+                    self.pc,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes one instruction. Returns `false` once the program has halted.
+    pub fn step(&mut self) -> Result<bool, EmulatorError> {
+        if self.halted {
+            return Ok(false);
+        }
+        let instr = match (self.pc as usize)
+            .checked_sub(1)
+            .and_then(|i| self.code.get(i))
+        {
+            Some(instr) => instr.clone(),
+            None => {
+                self.halted = true;
+                return Ok(false);
+            }
+        };
+
+        self.steps += 1;
+        let mut next = self.pc + 1;
+        let op1 = (instr.operand1_type, instr.operand1);
+        let op2 = (instr.operand2_type, instr.operand2);
+
+        match instr.instr {
+            MOV => {
+                let value = self.load(op2)?;
+                self.store(op1, value)?;
+            }
+            ADD => {
+                let value = self.load(op1)?.wrapping_add(self.load(op2)?);
+                self.store(op1, value)?;
+            }
+            SUB => {
+                let value = self.load(op1)?.wrapping_sub(self.load(op2)?);
+                self.store(op1, value)?;
+            }
+            MUL => {
+                let value = self.register(Register::R1).wrapping_mul(self.load(op1)?);
+                self.set_register(Register::R1, value);
+            }
+            DIV => {
+                let divisor = self.load(op2)?;
+                if divisor == 0 {
+                    return Err(EmulatorError::new("Division by zero".to_string(), self.pc));
+                }
+                let value = self.load(op1)?.wrapping_div(divisor);
+                self.store(op1, value)?;
+            }
+            POP => {
+                let value = self.stack.pop().ok_or_else(|| {
+                    EmulatorError::new("Pop from an empty stack".to_string(), self.pc)
+                })?;
+                self.store(op1, value)?;
+            }
+            ALLOW => {
+                let start = self.address(self.load(op1)?)?;
+                let len = self.address(self.load(op2)?)?;
+                let end = start
+                    .checked_add(len)
+                    .filter(|x| *x <= MAX_MEMORY_SIZE)
+                    .ok_or_else(|| {
+                        EmulatorError::new(
+                            format!(
+                                "Cannot allow {} bytes at #{}, memory is limited to {} bytes",
+                                len, start, MAX_MEMORY_SIZE
+                            ),
+                            self.pc,
+                        )
+                    })?;
+                if self.memory.len() < end {
+                    self.memory.resize(end, 0);
+                }
+            }
+            CALL => {
+                self.call_stack.push(next);
+                next = self.target(op1)?;
+            }
+            JUMP => {
+                next = self.target(op1)?;
+            }
+            RET => match self.call_stack.pop() {
+                Some(addr) => next = addr,
+                None => {
+                    self.halted = true;
+                    return Ok(false);
+                }
+            },
+            CMP => {
+                self.flags = Flags::compare(self.load(op1)?, self.load(op2)?);
+            }
+            NOP => {}
+            e => match opcode_condition(e) {
+                Some(cond) => {
+                    if self.flags.holds(cond) {
+                        next = self.target(op1)?;
+                    }
+                }
+                None => {
+                    return Err(EmulatorError::new(
+                        format!("Unknown opcode {:#04x}", e),
+                        self.pc,
+                    ))
+                }
+            },
+        }
+
+        self.pc = next;
+        Ok(true)
+    }
+
+    fn address(&self, value: i32) -> Result<usize, EmulatorError> {
+        usize::try_from(value)
+            .map_err(|_| EmulatorError::new(format!("Negative address {}", value), self.pc))
+    }
+
+    fn target(&self, (operand_type, operand): (u8, [u8; 4])) -> Result<u32, EmulatorError> {
+        match operand_type {
+            LABEL => Ok(u32::from_le_bytes(operand)),
+            e => Err(EmulatorError::new(
+                format!("Expected label operand, found operand type {:#04x}", e),
+                self.pc,
+            )),
+        }
+    }
+
+    fn register_index(&self, operand: [u8; 4]) -> Result<usize, EmulatorError> {
+        match u32::from_le_bytes(operand) {
+            reg @ 0x01..=0x10 => Ok(reg as usize - 1),
+            e => Err(EmulatorError::new(
+                format!("Invalid register {:#04x}", e),
+                self.pc,
+            )),
+        }
+    }
+
+    fn load(&self, (operand_type, operand): (u8, [u8; 4])) -> Result<i32, EmulatorError> {
+        match operand_type {
+            INT | LABEL => Ok(i32::from_le_bytes(operand)),
+            REG => Ok(self.registers[self.register_index(operand)?]),
+            MEM => self.read_memory(u32::from_le_bytes(operand) as usize),
+            e => Err(EmulatorError::new(
+                format!("Cannot read operand type {:#04x}", e),
+                self.pc,
+            )),
+        }
+    }
+
+    fn store(
+        &mut self,
+        (operand_type, operand): (u8, [u8; 4]),
+        value: i32,
+    ) -> Result<(), EmulatorError> {
+        match operand_type {
+            REG => {
+                let reg = self.register_index(operand)?;
+                self.registers[reg] = value;
+                Ok(())
+            }
+            MEM => self.write_memory(u32::from_le_bytes(operand) as usize, value),
+            e => Err(EmulatorError::new(
+                format!("Cannot write operand type {:#04x}", e),
+                self.pc,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::*;
+
+    fn label(name: &str, commands: Vec<Command>) -> Label {
+        Label::new(
+            name.to_string(),
+            commands.into_iter().map(Statement::from).collect(),
+        )
+    }
+
+    fn run(labels: Vec<Label>) -> Emulator {
+        let machine_code = MachineCodeCompiler::new(Program::new(labels))
+            .compile()
+            .unwrap();
+        let mut emulator = Emulator::new(machine_code);
+        emulator.run_with_limit(1000).unwrap();
+        emulator
+    }
+
+    fn reg(reg: Register) -> MemoryFetching {
+        MemoryFetching::Register(reg)
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let emulator = run(vec![label(
+            "main",
+            vec![
+                Command::Mov(Mov(reg(Register::R1), Expr::Int(7))),
+                Command::Mov(Mov(reg(Register::R2), Expr::Int(3))),
+                Command::Add(Add(reg(Register::R1), Expr::Memory(reg(Register::R2)))),
+                Command::Sub(Sub(reg(Register::R2), Expr::Int(5))),
+                Command::Mul(Mul(reg(Register::R2))),
+                Command::Mov(Mov(reg(Register::R3), Expr::Int(9))),
+                Command::Div(Div(reg(Register::R3), Expr::Int(2))),
+            ],
+        )]);
+        assert_eq!(emulator.register(Register::R1), -20);
+        assert_eq!(emulator.register(Register::R2), -2);
+        assert_eq!(emulator.register(Register::R3), 4);
+        assert!(emulator.is_halted());
+    }
+
+    #[test]
+    fn test_memory() {
+        let emulator = run(vec![label(
+            "main",
+            vec![
+                Command::Mov(Mov(MemoryFetching::Addr(8), Expr::Int(-42))),
+                Command::Mov(Mov(
+                    reg(Register::Rbp),
+                    Expr::Memory(MemoryFetching::Addr(8)),
+                )),
+                Command::Allow(Allow(Expr::Int(0x10000), Expr::Int(4))),
+                Command::Mov(Mov(MemoryFetching::Addr(0x10000), Expr::Int(1))),
+            ],
+        )]);
+        assert_eq!(emulator.register(Register::Rbp), -42);
+        assert_eq!(emulator.read_memory(8), Ok(-42));
+        assert_eq!(emulator.read_memory(0x10000), Ok(1));
+    }
+
+    #[test]
+    fn test_call_ret() {
+        let emulator = run(vec![
+            label(
+                "main",
+                vec![
+                    Command::Call(Call("double".to_string())),
+                    Command::Add(Add(reg(Register::R2), Expr::Int(1))),
+                    Command::Ret(Ret),
+                ],
+            ),
+            label(
+                "double",
+                vec![
+                    Command::Mov(Mov(reg(Register::R2), Expr::Int(21))),
+                    Command::Add(Add(reg(Register::R2), Expr::Memory(reg(Register::R2)))),
+                    Command::Ret(Ret),
+                ],
+            ),
+        ]);
+        assert_eq!(emulator.register(Register::R2), 43);
+        assert!(emulator.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_pop() {
+        let machine_code = MachineCodeCompiler::new(Program::new(vec![label(
+            "main",
+            vec![Command::Pop(Pop(reg(Register::R4)))],
+        )]))
+        .compile()
+        .unwrap();
+        let mut emulator = Emulator::new(machine_code);
+        emulator.push(5);
+        emulator.run().unwrap();
+        assert_eq!(emulator.register(Register::R4), 5);
+        assert_eq!(emulator.step(), Ok(false), "a halted emulator does not run");
+    }
+
+    #[test]
+    fn test_errors() {
+        let machine_code = MachineCodeCompiler::new(Program::new(vec![label(
+            "main",
+            vec![
+                Command::Mov(Mov(reg(Register::R1), Expr::Int(1))),
+                Command::Div(Div(reg(Register::R1), Expr::Int(0))),
+            ],
+        )]))
+        .compile()
+        .unwrap();
+        let err = Emulator::new(machine_code.clone()).run().unwrap_err();
+        assert_eq!(err, EmulatorError::new("Division by zero".to_string(), 2));
+
+        let machine_code = MachineCodeCompiler::new(Program::new(vec![label(
+            "main",
+            vec![Command::Call(Call("main".to_string()))],
+        )]))
+        .compile()
+        .unwrap();
+        let err = Emulator::new(machine_code).run_with_limit(10).unwrap_err();
+        assert_eq!(err.message, "Step limit of 10 reached");
+
+        let machine_code = MachineCodeCompiler::new(Program::new(vec![label(
+            "main",
+            vec![
+                Command::Allow(Allow(Expr::Int(0), Expr::Int(MAX_MEMORY_SIZE as i32))),
+                Command::Allow(Allow(Expr::Int(1), Expr::Int(i32::MAX))),
+            ],
+        )]))
+        .compile()
+        .unwrap();
+        let mut emulator = Emulator::new(machine_code);
+        let err = emulator.run().unwrap_err();
+        assert_eq!(
+            err,
+            EmulatorError::new(
+                format!(
+                    "Cannot allow {} bytes at #1, memory is limited to {} bytes",
+                    i32::MAX,
+                    MAX_MEMORY_SIZE
+                ),
+                2
+            )
+        );
+        assert_eq!(emulator.memory.len(), MAX_MEMORY_SIZE);
+    }
+
+    #[test]
+    fn test_jump_source() {
+        let source =
+            "main:\n    jmp $skip\n    mov r1, 1\nskip:\n    mov r2, 2\n    ret\n    mov r3, 3\n";
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let emulator = run(program.labels);
+        assert_eq!(emulator.register(Register::R1), 0);
+        assert_eq!(emulator.register(Register::R2), 2);
+        assert_eq!(emulator.register(Register::R3), 0);
+    }
+
+    #[test]
+    fn test_flags() {
+        for (a, b, expected) in [
+            (1, 1, [true, false, false, false, true, true]),
+            (1, 2, [false, true, true, false, true, false]),
+            (2, 1, [false, true, false, true, false, true]),
+            (i32::MIN, 1, [false, true, true, false, true, false]),
+        ] {
+            let flags = Flags::compare(a, b);
+            let holds = Condition::ALL.map(|x| flags.holds(x));
+            assert_eq!(holds, expected, "cmp {}, {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_loop() {
+        let source = r#"
+        main:
+            mov r1, 0
+            mov r2, 1
+        loop:
+            add r1, r2
+            add r2, 1
+            cmp r2, 10
+            jle $loop
+            cmp r1, 55
+            jne $fail
+            mov r3, 1
+            ret
+        fail:
+            mov r3, 2
+        "#;
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let emulator = run(program.labels);
+        assert_eq!(emulator.register(Register::R1), 55);
+        assert_eq!(emulator.register(Register::R3), 1);
+    }
+
+    #[test]
+    fn test_data() {
+        let source = r#"
+        main:
+            mov r1, $numbers
+            mov r2, #32770
+            mov r3, $msg
+            ret
+        msg:
+            .string "hi"
+        numbers:
+            .word 7, 65534
+        "#;
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let emulator = run(program.labels);
+        assert_eq!(emulator.register(Register::R1), DATA_START as i32 + 3);
+        assert_eq!(emulator.register(Register::R2), 0x0700);
+        assert_eq!(emulator.register(Register::R3), DATA_START as i32);
+        assert_eq!(emulator.read_memory(DATA_START as usize + 3), Ok(7));
+        assert_eq!(emulator.read_memory(DATA_START as usize + 7), Ok(65534));
+    }
+
+    #[test]
+    fn test_jump() {
+        let machine_code = MachineCode::new(vec![
+            MachineCodeInstruction::new(
+                [0x1, 0x0, 0x0, 0x0],
+                JUMP,
+                LABEL,
+                [0x3, 0x0, 0x0, 0x0],
+                VOID,
+                Default::default(),
+            ),
+            MachineCodeInstruction::new(
+                [0x1, 0x0, 0x0, 0x0],
+                MOV,
+                REG,
+                [0x1, 0x0, 0x0, 0x0],
+                INT,
+                [0x1, 0x0, 0x0, 0x0],
+            ),
+            MachineCodeInstruction::new(
+                [0x3, 0x0, 0x0, 0x0],
+                MOV,
+                REG,
+                [0x2, 0x0, 0x0, 0x0],
+                INT,
+                [0x2, 0x0, 0x0, 0x0],
+            ),
+        ]);
+        let mut emulator = Emulator::new(machine_code);
+        emulator.run().unwrap();
+        assert_eq!(emulator.register(Register::R1), 0);
+        assert_eq!(emulator.register(Register::R2), 2);
+    }
+}
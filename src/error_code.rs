@@ -0,0 +1,523 @@
+//! Stable codes for every kind of error the assembler reports.
+//!
+//! Messages may be reworded, but a code keeps its meaning once published.
+//! `popper_asm explain E0001` prints [`ErrorCode::explanation`].
+
+use std::fmt::{Display, Formatter};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorCode {
+    UnexpectedCharacter = 1,
+    UnterminatedString,
+    UnexpectedToken,
+    UnknownInstruction,
+    UnknownRegister,
+    InvalidNumber,
+    UnknownDirective,
+    UnknownEscape,
+    InvalidMacro,
+    UnterminatedMacro,
+    DuplicateMacro,
+    MacroArguments,
+    RecursiveMacro,
+    ReservedConstantName,
+    InvalidInclude,
+    IncludeNotFound,
+    IncludeCycle,
+    UnknownLabel,
+    DuplicateLabel,
+    InvalidOperand,
+    MixedLabel,
+    UnknownConstant,
+    DuplicateConstant,
+    RecursiveConstant,
+    InvalidExpression,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 25] = [
+        ErrorCode::UnexpectedCharacter,
+        ErrorCode::UnterminatedString,
+        ErrorCode::UnexpectedToken,
+        ErrorCode::UnknownInstruction,
+        ErrorCode::UnknownRegister,
+        ErrorCode::InvalidNumber,
+        ErrorCode::UnknownDirective,
+        ErrorCode::UnknownEscape,
+        ErrorCode::InvalidMacro,
+        ErrorCode::UnterminatedMacro,
+        ErrorCode::DuplicateMacro,
+        ErrorCode::MacroArguments,
+        ErrorCode::RecursiveMacro,
+        ErrorCode::ReservedConstantName,
+        ErrorCode::InvalidInclude,
+        ErrorCode::IncludeNotFound,
+        ErrorCode::IncludeCycle,
+        ErrorCode::UnknownLabel,
+        ErrorCode::DuplicateLabel,
+        ErrorCode::InvalidOperand,
+        ErrorCode::MixedLabel,
+        ErrorCode::UnknownConstant,
+        ErrorCode::DuplicateConstant,
+        ErrorCode::RecursiveConstant,
+        ErrorCode::InvalidExpression,
+    ];
+
+    /// The code as written in diagnostics, such as `E0001`.
+    pub fn code(&self) -> String {
+        format!("E{:04}", *self as u16)
+    }
+
+    /// Parses `E0001`, also accepting `e0001` and `1`.
+    pub fn from_code(code: &str) -> Option<Self> {
+        let number = code.trim_start_matches(['E', 'e']).parse::<usize>().ok()?;
+        Self::ALL.get(number.checked_sub(1)?).copied()
+    }
+
+    /// A short summary, printed as the title of the explanation.
+    pub fn title(&self) -> &'static str {
+        match self {
+            ErrorCode::UnexpectedCharacter => "A character that cannot start a token",
+            ErrorCode::UnterminatedString => "A string without its closing quote",
+            ErrorCode::UnexpectedToken => "A token where the syntax expects another",
+            ErrorCode::UnknownInstruction => "An unknown instruction",
+            ErrorCode::UnknownRegister => "An unknown register",
+            ErrorCode::InvalidNumber => "A number out of range",
+            ErrorCode::UnknownDirective => "An unknown directive",
+            ErrorCode::UnknownEscape => "An unknown escape in a string",
+            ErrorCode::InvalidMacro => "A malformed macro definition",
+            ErrorCode::UnterminatedMacro => "A macro without `.endm`",
+            ErrorCode::DuplicateMacro => "A macro defined twice",
+            ErrorCode::MacroArguments => "A macro called with the wrong number of arguments",
+            ErrorCode::RecursiveMacro => "A macro that calls itself",
+            ErrorCode::ReservedConstantName => "A constant named after a register",
+            ErrorCode::InvalidInclude => "A malformed or unsupported `.include`",
+            ErrorCode::IncludeNotFound => "An included file that cannot be read",
+            ErrorCode::IncludeCycle => "A file that includes itself",
+            ErrorCode::UnknownLabel => "A reference to an undefined label",
+            ErrorCode::DuplicateLabel => "A label defined twice",
+            ErrorCode::InvalidOperand => "An operand the instruction cannot encode",
+            ErrorCode::MixedLabel => "A label holding both instructions and data",
+            ErrorCode::UnknownConstant => "A reference to an undefined constant",
+            ErrorCode::DuplicateConstant => "A constant defined twice",
+            ErrorCode::RecursiveConstant => "A constant defined in terms of itself",
+            ErrorCode::InvalidExpression => "A constant expression that cannot be evaluated",
+        }
+    }
+
+    /// A description of the error, with an example and its fix.
+    pub fn explanation(&self) -> &'static str {
+        match self {
+            ErrorCode::UnexpectedCharacter => {
+                "\
+The lexer found a character that is not part of any PopperASM token.
+Comments start with `;`, directives with `.` directly followed by a name.
+
+Erroneous example:
+
+    main:
+        mov r1, 1 @ set r1
+
+Fix:
+
+    main:
+        mov r1, 1 ; set r1"
+            }
+            ErrorCode::UnterminatedString => {
+                "\
+A string was opened with `\"` but the line ended before it was closed.
+Strings cannot span lines; use `\\n` for a newline.
+
+Erroneous example:
+
+    msg: .string \"hello
+
+Fix:
+
+    msg: .string \"hello\""
+            }
+            ErrorCode::UnexpectedToken => {
+                "\
+The parser found a token that does not fit the syntax at that point, such
+as a missing comma between operands or extra text at the end of a line.
+
+Erroneous example:
+
+    main:
+        mov r1 2
+
+Fix:
+
+    main:
+        mov r1, 2"
+            }
+            ErrorCode::UnknownInstruction => {
+                "\
+A line inside a label starts with a name that is neither an instruction
+nor a macro defined earlier in the program.
+
+Erroneous example:
+
+    main:
+        move r1, 2
+
+Fix:
+
+    main:
+        mov r1, 2"
+            }
+            ErrorCode::UnknownRegister => {
+                "\
+An operand that must be a register names something else. The registers
+are `r1` to `r15` and `rbp`.
+
+Erroneous example:
+
+    main:
+        pop r16
+
+Fix:
+
+    main:
+        pop r15"
+            }
+            ErrorCode::InvalidNumber => {
+                "\
+An integer does not fit in 32 bits, or an address is not a valid number.
+
+Erroneous example:
+
+    main:
+        mov r1, 4294967296
+
+Fix:
+
+    main:
+        mov r1, 2147483647"
+            }
+            ErrorCode::UnknownDirective => {
+                "\
+A directive is not one of `.byte`, `.word`, `.string`, `.zero`, `.equ`,
+`.const`, `.macro`, `.endm` or `.include`.
+
+Erroneous example:
+
+    table: .bytes 1, 2
+
+Fix:
+
+    table: .byte 1, 2"
+            }
+            ErrorCode::UnknownEscape => {
+                "\
+A `\\` in a string is followed by a character that has no escape. The
+escapes are `\\n`, `\\t`, `\\0`, `\\\\` and `\\\"`.
+
+Erroneous example:
+
+    msg: .string \"C:\\dir\"
+
+Fix:
+
+    msg: .string \"C:\\\\dir\""
+            }
+            ErrorCode::InvalidMacro => {
+                "\
+A `.macro` line must give a name and then the parameter names, separated
+by commas. Neither may be an instruction or register name, and a macro
+cannot be defined inside another one.
+
+Erroneous example:
+
+    .macro mov a, b
+        add a, b
+    .endm
+
+Fix:
+
+    .macro add_to a, b
+        add a, b
+    .endm"
+            }
+            ErrorCode::UnterminatedMacro => {
+                "\
+The body of a macro runs until `.endm`, which is missing.
+
+Erroneous example:
+
+    .macro clear a
+        mov a, 0
+
+Fix:
+
+    .macro clear a
+        mov a, 0
+    .endm"
+            }
+            ErrorCode::DuplicateMacro => {
+                "\
+Two macros have the same name. Rename one of them.
+
+Erroneous example:
+
+    .macro clear a
+        mov a, 0
+    .endm
+    .macro clear a
+        sub a, a
+    .endm
+
+Fix:
+
+    .macro clear a
+        mov a, 0
+    .endm"
+            }
+            ErrorCode::MacroArguments => {
+                "\
+A macro was called with a different number of arguments than it has
+parameters.
+
+Erroneous example:
+
+    .macro swap a, b
+        mov r15, a
+        mov a, b
+        mov b, r15
+    .endm
+    main:
+        swap r1
+
+Fix:
+
+    main:
+        swap r1, r2"
+            }
+            ErrorCode::RecursiveMacro => {
+                "\
+Expanding a macro led back to the same macro, which would never end.
+
+Erroneous example:
+
+    .macro twice x
+        twice x
+    .endm
+
+Fix:
+
+    .macro twice x
+        add x, x
+    .endm"
+            }
+            ErrorCode::ReservedConstantName => {
+                "\
+A constant cannot have the name of a register, which an operand with that
+name would always mean.
+
+Erroneous example:
+
+    .equ r1, 4
+
+Fix:
+
+    .equ SIZE, 4"
+            }
+            ErrorCode::InvalidInclude => {
+                "\
+`.include` must be followed by a file name in quotes, alone on its line.
+It is only available when assembling a file, not a string.
+
+Erroneous example:
+
+    .include macros.popasm
+
+Fix:
+
+    .include \"macros.popasm\""
+            }
+            ErrorCode::IncludeNotFound => {
+                "\
+An included file was not found next to the including file nor in any
+directory given with `-I`, or it could not be read.
+
+Erroneous example:
+
+    .include \"macros.popasm\"    ; stored in lib/
+
+Fix: pass the directory on the command line.
+
+    popper_asm assemble main.popasm -I lib"
+            }
+            ErrorCode::IncludeCycle => {
+                "\
+A file includes itself, directly or through other files, which would
+never end. The message lists the files in the cycle.
+
+Erroneous example:
+
+    ; a.popasm
+    .include \"b.popasm\"
+    ; b.popasm
+    .include \"a.popasm\"
+
+Fix: move what both files need to a third file that they both include."
+            }
+            ErrorCode::UnknownLabel => {
+                "\
+A `call`, a jump or a `$label` operand names a label that is not defined
+anywhere in the program.
+
+Erroneous example:
+
+    main:
+        call $print
+
+Fix:
+
+    main:
+        call $print
+    print:
+        ret"
+            }
+            ErrorCode::DuplicateLabel => {
+                "\
+Two labels have the same name. Rename one of them.
+
+Erroneous example:
+
+    loop:
+        add r1, 1
+    loop:
+        jmp $loop
+
+Fix:
+
+    loop:
+        add r1, 1
+    again:
+        jmp $loop"
+            }
+            ErrorCode::InvalidOperand => {
+                "\
+An operand cannot be encoded for the instruction it is used with, for
+example a jump to a data label, an address that does not fit in 32 bits
+or a data directive among instructions.
+
+Erroneous example:
+
+    msg: .string \"hi\"
+    main:
+        jmp $msg
+
+Fix:
+
+    main:
+        mov r1, $msg"
+            }
+            ErrorCode::MixedLabel => {
+                "\
+A label holds either instructions or data directives, not both, because
+data is stored apart from the code.
+
+Erroneous example:
+
+    main:
+        mov r1, $count
+        .word 0
+
+Fix:
+
+    main:
+        mov r1, $count
+    count:
+        .word 0"
+            }
+            ErrorCode::UnknownConstant => {
+                "\
+An operand uses a name that is neither a register nor a constant defined
+with `.equ` or `.const`.
+
+Erroneous example:
+
+    main:
+        mov r1, SIZE
+
+Fix:
+
+    .equ SIZE, 4
+    main:
+        mov r1, SIZE"
+            }
+            ErrorCode::DuplicateConstant => {
+                "\
+Two constants have the same name. Rename or remove one of them.
+
+Erroneous example:
+
+    .equ SIZE, 4
+    .equ SIZE, 8
+
+Fix:
+
+    .equ SIZE, 4
+    .equ BIG_SIZE, 8"
+            }
+            ErrorCode::RecursiveConstant => {
+                "\
+A constant is defined in terms of itself, directly or through other
+constants, so it has no value.
+
+Erroneous example:
+
+    .equ A, B + 1
+    .equ B, A * 2
+
+Fix:
+
+    .equ A, B + 1
+    .equ B, 2"
+            }
+            ErrorCode::InvalidExpression => {
+                "\
+A constant expression cannot be evaluated: it divides by zero, overflows
+32 bits, reads a register or memory, or uses a label address where the
+addresses are not known yet, such as the count of `.zero`.
+
+Erroneous example:
+
+    main:
+        mov r1, 10 / (2 - 2)
+
+Fix:
+
+    main:
+        mov r1, 10 / 2"
+            }
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        for (i, code) in ErrorCode::ALL.iter().enumerate() {
+            assert_eq!(*code as usize, i + 1);
+            assert_eq!(ErrorCode::from_code(&code.code()), Some(*code));
+        }
+        assert_eq!(ErrorCode::UnexpectedCharacter.code(), "E0001");
+        assert_eq!(ErrorCode::InvalidExpression.to_string(), "E0025");
+        assert_eq!(ErrorCode::from_code("e18"), Some(ErrorCode::UnknownLabel));
+        assert_eq!(ErrorCode::from_code("E0000"), None);
+        assert_eq!(ErrorCode::from_code("E0026"), None);
+        assert_eq!(ErrorCode::from_code("label"), None);
+    }
+}
@@ -0,0 +1,176 @@
+//! Constant expressions.
+//!
+//! `MachineCodeCompiler` folds every operand with [`Evaluator::eval`] before
+//! encoding it. Arithmetic is done on `i32` and fails on overflow or division
+//! by zero. A `$label` stands for its address, and a constant for the value of
+//! its definition, evaluated where it is used.
+
+use crate::ast::*;
+use crate::machine_code::{CompileError, CompileErrorKind};
+use crate::span::Span;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct Evaluator<'a> {
+    constants: &'a HashMap<String, Constant>,
+    /// `None` while label addresses are being assigned, when no label can be used.
+    labels: Option<&'a HashMap<String, u32>>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(
+        constants: &'a HashMap<String, Constant>,
+        labels: Option<&'a HashMap<String, u32>>,
+    ) -> Self {
+        Self { constants, labels }
+    }
+
+    /// Folds `expr` into an `Expr::Int`, or returns it unchanged when it is a
+    /// plain `Expr::Label` or `Expr::Memory` operand.
+    pub fn eval(&self, expr: &Expr, span: Span) -> Result<Expr, CompileError> {
+        match expr {
+            Expr::Int(_) | Expr::Label(_) | Expr::Memory(_) => Ok(expr.clone()),
+            _ => Ok(Expr::Int(self.value(expr, span)?)),
+        }
+    }
+
+    /// Value of `expr`, which must not read a register or memory.
+    pub fn value(&self, expr: &Expr, span: Span) -> Result<i32, CompileError> {
+        self.value_in(expr, span, &mut vec![])
+    }
+
+    /// `stack` holds the constants being evaluated, to catch definitions
+    /// that refer to themselves.
+    fn value_in(
+        &self,
+        expr: &Expr,
+        span: Span,
+        stack: &mut Vec<String>,
+    ) -> Result<i32, CompileError> {
+        let error = |kind| CompileError::new(kind, span);
+        let invalid = |message: String| error(CompileErrorKind::InvalidExpression(message));
+
+        match expr {
+            Expr::Int(int) => Ok(*int),
+            Expr::Label(label) => {
+                let labels = self.labels.ok_or_else(|| {
+                    invalid(format!("The address of `${}` is not known here", label))
+                })?;
+                match labels.get(label) {
+                    Some(addr) => Ok(*addr as i32),
+                    None => Err(error(CompileErrorKind::UnknownLabel(label.clone()))),
+                }
+            }
+            Expr::Memory(mem) => Err(invalid(format!("`{}` is not a constant", mem))),
+            Expr::Const(name) => {
+                let constant = self
+                    .constants
+                    .get(name)
+                    .ok_or_else(|| error(CompileErrorKind::UnknownConstant(name.clone())))?;
+                if stack.contains(name) {
+                    return Err(error(CompileErrorKind::RecursiveConstant(name.clone())));
+                }
+                stack.push(name.clone());
+                let value = self.value_in(&constant.value, span, stack);
+                stack.pop();
+                value
+            }
+            Expr::Unary(UnaryOp::Neg, expr) => self
+                .value_in(expr, span, stack)?
+                .checked_neg()
+                .ok_or_else(|| invalid("Arithmetic overflow".to_string())),
+            Expr::Binary(op, left, right) => {
+                let left = self.value_in(left, span, stack)?;
+                let right = self.value_in(right, span, stack)?;
+                if right == 0 && matches!(op, BinaryOp::Div | BinaryOp::Rem) {
+                    return Err(invalid("Division by zero".to_string()));
+                }
+                let value = match op {
+                    BinaryOp::Add => left.checked_add(right),
+                    BinaryOp::Sub => left.checked_sub(right),
+                    BinaryOp::Mul => left.checked_mul(right),
+                    BinaryOp::Div => left.checked_div(right),
+                    BinaryOp::Rem => left.checked_rem(right),
+                };
+                value.ok_or_else(|| invalid("Arithmetic overflow".to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(int: i32) -> Box<Expr> {
+        Box::new(Expr::Int(int))
+    }
+
+    #[test]
+    fn test_value() {
+        let mut constants = HashMap::new();
+        for (name, value) in [
+            (
+                "SIZE",
+                Expr::Binary(BinaryOp::Mul, int(4), Box::new(Expr::Const("WORDS".into()))),
+            ),
+            ("WORDS", Expr::Int(3)),
+            ("LOOP", Expr::Const("LOOP".into())),
+        ] {
+            constants.insert(name.to_string(), Constant::new(name.to_string(), value));
+        }
+        let labels = HashMap::from([("end".to_string(), 9), ("start".to_string(), 2)]);
+        let evaluator = Evaluator::new(&constants, Some(&labels));
+        let span = Span::new(1, 2);
+
+        let expr = Expr::Binary(
+            BinaryOp::Add,
+            Box::new(Expr::Unary(
+                UnaryOp::Neg,
+                Box::new(Expr::Const("SIZE".into())),
+            )),
+            Box::new(Expr::Binary(
+                BinaryOp::Sub,
+                Box::new(Expr::Label("end".into())),
+                Box::new(Expr::Label("start".into())),
+            )),
+        );
+        assert_eq!(evaluator.value(&expr, span), Ok(-5));
+        assert_eq!(
+            evaluator.eval(&Expr::Label("end".into()), span),
+            Ok(Expr::Label("end".into()))
+        );
+
+        let errors = [
+            Expr::Const("LOOP".into()),
+            Expr::Const("MISSING".into()),
+            Expr::Binary(BinaryOp::Rem, int(1), int(0)),
+            Expr::Binary(BinaryOp::Mul, int(i32::MAX), int(2)),
+            Expr::Binary(
+                BinaryOp::Add,
+                int(1),
+                Box::new(Expr::Memory(MemoryFetching::Register(Register::R1))),
+            ),
+        ]
+        .map(|x| evaluator.value(&x, span).unwrap_err().message());
+        assert_eq!(
+            errors,
+            [
+                "Constant `LOOP` is defined in terms of itself",
+                "Unknown constant `MISSING`",
+                "Division by zero",
+                "Arithmetic overflow",
+                "`r1` is not a constant",
+            ]
+        );
+
+        let evaluator = Evaluator::new(&constants, None);
+        assert_eq!(
+            evaluator
+                .value(&Expr::Label("end".into()), span)
+                .unwrap_err()
+                .message(),
+            "The address of `$end` is not known here"
+        );
+    }
+}
@@ -0,0 +1,363 @@
+//! Canonical layout for PopperASM source.
+//!
+//! [`format`] rewrites one file line by line:
+//!
+//! - labels start at column 0, with one blank line before each one
+//! - instructions and data directives are indented by four spaces, with the
+//!   operands of a run of lines starting in the same column
+//! - `.equ`, `.const`, `.macro`, `.endm` and `.include` start at column 0
+//! - comments are kept; trailing comments of a run of lines are aligned
+//!
+//! A line that the parser turned into a statement or constant is written
+//! from its `ast` node, which spells operands canonically. Other lines, such
+//! as macro definitions and calls, are written from their tokens.
+
+use crate::ast::{Constant, Program, Statement};
+use crate::lexer::{Comment, Lexer, Token, TokenKind};
+use crate::source_map::SourceFile;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const INDENT: &str = "    ";
+
+/// Formats `source`, which parsed into `program`. `program` may hold
+/// statements from other files, as `.include` puts them in; only those of
+/// file 0 are used.
+pub fn format(program: &Program, source: &str) -> String {
+    let mut lexer = Lexer::new(source);
+    let _ = lexer.scan_tokens();
+    let file = SourceFile::new(PathBuf::new(), source.to_string());
+
+    let mut tokens: Vec<Vec<Token>> = vec![vec![]; file.line_count() + 1];
+    for token in lexer.get_tokens() {
+        if token.token_kind != TokenKind::Newline {
+            tokens[file.line_col(token.span.start()).0 - 1].push(token);
+        }
+    }
+    let mut comments: Vec<Option<Comment>> = vec![None; tokens.len()];
+    for comment in lexer.get_comments() {
+        let (line, _) = file.line_col(comment.span.start());
+        comments[line - 1] = Some(comment);
+    }
+
+    let statements = program
+        .labels
+        .iter()
+        .flat_map(|x| &x.program)
+        .filter(|x| x.span.file() == 0 && x.call_site.is_none())
+        .map(|x| (x.span.start(), x))
+        .collect::<HashMap<_, _>>();
+    let constants = program
+        .constants
+        .iter()
+        .filter(|x| x.span.file() == 0)
+        .map(|x| (x.span.start(), x))
+        .collect::<HashMap<_, _>>();
+    let nodes = Nodes {
+        statements,
+        constants,
+    };
+
+    let mut lines = vec![];
+    for (tokens, comment) in tokens.iter().zip(comments) {
+        let comment = comment.map(|x| x.text);
+        let tokens = tokens.as_slice();
+        match tokens {
+            [] => match comment {
+                Some(comment) => lines.push(Line::Comment(comment)),
+                None => lines.push(Line::Blank),
+            },
+            [name, colon, rest @ ..]
+                if name.token_kind == TokenKind::Ident && colon.token_kind == TokenKind::Colon =>
+            {
+                if rest.is_empty() {
+                    lines.push(Line::Label(name.lexeme.clone(), comment));
+                } else {
+                    lines.push(Line::Label(name.lexeme.clone(), None));
+                    lines.push(nodes.line(rest, comment));
+                }
+            }
+            _ => lines.push(nodes.line(tokens, comment)),
+        }
+    }
+
+    render(&tidy(lines))
+}
+
+/// The `ast` nodes of file 0, by the offset of their first token.
+struct Nodes<'a> {
+    statements: HashMap<usize, &'a Statement>,
+    /// Keyed by the offset of the constant name.
+    constants: HashMap<usize, &'a Constant>,
+}
+
+impl Nodes<'_> {
+    /// The line holding `tokens`, which are not empty.
+    fn line(&self, tokens: &[Token], comment: Option<String>) -> Line {
+        let first = &tokens[0];
+        let top_level = first.token_kind == TokenKind::Directive
+            && matches!(
+                first.lexeme.as_str(),
+                ".equ" | ".const" | ".macro" | ".endm" | ".include"
+            );
+
+        let text = if let Some(statement) = self.statements.get(&first.span.start()) {
+            statement.to_string()
+        } else if let Some(constant) = tokens
+            .get(1)
+            .and_then(|x| self.constants.get(&x.span.start()))
+        {
+            constant.to_string()
+        } else {
+            join_tokens(tokens)
+        };
+        let (mnemonic, operands) = match text.split_once(' ') {
+            Some((mnemonic, operands)) => (mnemonic.to_string(), operands.to_string()),
+            None => (text, String::new()),
+        };
+
+        let code = Code {
+            mnemonic,
+            operands,
+            comment,
+        };
+        match top_level {
+            true => Line::Directive(code),
+            false => Line::Statement(code),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Line {
+    Blank,
+    /// A line holding only a comment.
+    Comment(String),
+    Label(String, Option<String>),
+    /// An instruction, data directive or macro call, indented.
+    Statement(Code),
+    /// A directive written at column 0.
+    Directive(Code),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Code {
+    mnemonic: String,
+    operands: String,
+    comment: Option<String>,
+}
+
+/// Writes `tokens` with a space between them, except around `,`, `$`, `#`,
+/// parentheses and unary `-`.
+fn join_tokens(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    let mut glue = true;
+    let mut previous: Option<TokenKind> = None;
+    for token in tokens {
+        let kind = token.token_kind;
+        if !glue && !matches!(kind, TokenKind::Comma | TokenKind::RightParen) {
+            text.push(' ');
+        }
+        text.push_str(&token.lexeme);
+
+        let unary = kind == TokenKind::Minus
+            && previous.is_none_or(|x| {
+                matches!(
+                    x,
+                    TokenKind::Comma
+                        | TokenKind::LeftParen
+                        | TokenKind::Plus
+                        | TokenKind::Minus
+                        | TokenKind::Star
+                        | TokenKind::Slash
+                        | TokenKind::Percent
+                )
+            });
+        glue = unary
+            || matches!(
+                kind,
+                TokenKind::Dollar | TokenKind::Hashtag | TokenKind::LeftParen
+            );
+        // the mnemonic is followed by a space even before `$` or `#`
+        if previous.is_none() {
+            glue = false;
+        }
+        previous = Some(kind);
+    }
+    text
+}
+
+/// Drops blank lines at both ends and after labels, merges runs of blank
+/// lines, and puts one blank line before every label and the comments just
+/// above it.
+fn tidy(lines: Vec<Line>) -> Vec<Line> {
+    let mut tidy: Vec<Line> = vec![];
+    for line in lines {
+        let after_blank = matches!(tidy.last(), None | Some(Line::Blank | Line::Label(..)));
+        if line == Line::Blank && after_blank {
+            continue;
+        }
+        if let Line::Label(..) = line {
+            let start = tidy.len()
+                - tidy
+                    .iter()
+                    .rev()
+                    .take_while(|x| matches!(x, Line::Comment(_)))
+                    .count();
+            if start > 0 && tidy[start - 1] != Line::Blank {
+                tidy.insert(start, Line::Blank);
+            }
+        }
+        tidy.push(line);
+    }
+    while tidy.last() == Some(&Line::Blank) {
+        tidy.pop();
+    }
+    tidy
+}
+
+fn render(lines: &[Line]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        match &lines[i] {
+            Line::Blank => out.push('\n'),
+            Line::Label(name, comment) => {
+                out += &format!("{}:", name);
+                if let Some(comment) = comment {
+                    out += &format!(" {}", comment);
+                }
+                out.push('\n');
+            }
+            Line::Comment(comment) => {
+                let indent = match lines[i..].iter().find(|x| !matches!(x, Line::Comment(_))) {
+                    Some(Line::Statement(_)) => INDENT,
+                    _ => "",
+                };
+                out += &format!("{}{}\n", indent, comment);
+            }
+            Line::Directive(code) => {
+                out += &format!("{}\n", code_line(code, 0, 0));
+            }
+            Line::Statement(_) => {
+                let run = lines[i..]
+                    .iter()
+                    .map_while(|x| match x {
+                        Line::Statement(code) => Some(code),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let mnemonic_width = run.iter().map(|x| x.mnemonic.len()).max().unwrap_or(0);
+                let code_width = run
+                    .iter()
+                    .map(|x| code_text(x, mnemonic_width).len())
+                    .max()
+                    .unwrap_or(0);
+                for code in &run {
+                    out += &format!(
+                        "{}{}\n",
+                        INDENT,
+                        code_line(code, mnemonic_width, code_width)
+                    );
+                }
+                i += run.len();
+                continue;
+            }
+        }
+        i += 1;
+    }
+    out
+}
+
+/// `code` with its mnemonic padded to `mnemonic_width` and the code before
+/// its comment padded to `code_width`.
+fn code_line(code: &Code, mnemonic_width: usize, code_width: usize) -> String {
+    let mut line = code_text(code, mnemonic_width);
+    if let Some(comment) = &code.comment {
+        line += &format!(
+            "{:width$} {}",
+            "",
+            comment,
+            width = code_width.saturating_sub(line.len())
+        );
+    }
+    line
+}
+
+/// `code` without its comment.
+fn code_text(code: &Code, mnemonic_width: usize) -> String {
+    match code.operands.as_str() {
+        "" => code.mnemonic.clone(),
+        operands => format!(
+            "{:width$} {}",
+            code.mnemonic,
+            operands,
+            width = mnemonic_width
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_string;
+
+    fn fmt(source: &str) -> String {
+        format(&parse_string(source).unwrap(), source)
+    }
+
+    #[test]
+    fn test_format() {
+        let source = "\
+.macro swap a,b
+  mov r15,a
+  mov a , b
+.endm
+; entry point
+.const   SIZE,4*(2+1)
+main:   mov r1,SIZE ; size
+  call $print
+
+
+        cmp r1 , -1
+   jle $done ; negative
+   swap r1,r2
+        // the end
+done:
+\tret
+msg: .string \"hi\\n\"
+print:
+    ; print
+    add #4, 1*2
+";
+        let expected = "\
+.macro swap a, b
+    mov r15, a
+    mov a, b
+.endm
+; entry point
+.equ SIZE, 4 * (2 + 1)
+
+main:
+    mov  r1, SIZE ; size
+    call $print
+
+    cmp  r1, -1
+    jle  $done  ; negative
+    swap r1, r2
+
+// the end
+done:
+    ret
+
+msg:
+    .string \"hi\\n\"
+
+print:
+    ; print
+    add #4, 1 * 2
+";
+        assert_eq!(fmt(source), expected);
+        assert_eq!(fmt(expected), expected);
+    }
+}
@@ -0,0 +1,239 @@
+//! `.include "file"` handling.
+//!
+//! The [`Loader`] lexes a file and replaces every `.include "name"` line with
+//! the tokens of the named file, before anything is parsed. `name` is looked
+//! up next to the including file first, then in each search path in order.
+//! Every loaded file is added to the loader's [`SourceMap`], and its spans
+//! carry its [`FileId`] there.
+
+use crate::error_code::ErrorCode;
+use crate::lexer::{Lexer, Token, TokenKind};
+use crate::parser::Error;
+use crate::source_map::SourceMap;
+use crate::span::FileId;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default)]
+pub struct Loader {
+    pub search_paths: Vec<PathBuf>,
+    /// Every file loaded so far.
+    sources: SourceMap,
+    /// Canonical paths of the files being included, outermost first.
+    stack: Vec<PathBuf>,
+    errors: Vec<Error>,
+}
+
+impl Loader {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        Self {
+            search_paths,
+            ..Self::default()
+        }
+    }
+
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    pub fn into_sources(self) -> SourceMap {
+        self.sources
+    }
+
+    /// Lexes `source`, read from `path`, and every file it includes. On
+    /// failure every lexer and include error is returned.
+    pub fn load(&mut self, path: &Path, source: String) -> Result<Vec<Token>, Vec<Error>> {
+        self.errors.clear();
+        let file = self.sources.add(path.to_path_buf(), source);
+        self.stack = vec![canonical(path)];
+        let tokens = self.tokens(file);
+
+        if self.errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(self.errors.clone())
+        }
+    }
+
+    /// Tokens of `file`, with its includes expanded.
+    fn tokens(&mut self, file: FileId) -> Vec<Token> {
+        let mut lexer = Lexer::new(&self.sources.files()[file].source).with_file(file);
+        if let Err(errors) = lexer.scan_tokens() {
+            self.errors.extend(errors);
+        }
+
+        let mut tokens = vec![];
+        let mut lexed = lexer.get_tokens().into_iter().peekable();
+        while let Some(token) = lexed.next() {
+            let at_line_start = tokens
+                .last()
+                .is_none_or(|x: &Token| x.token_kind == TokenKind::Newline);
+            if !(at_line_start
+                && token.token_kind == TokenKind::Directive
+                && token.lexeme == ".include")
+            {
+                tokens.push(token);
+                continue;
+            }
+
+            let name = lexed.next_if(|x| x.token_kind == TokenKind::String);
+            let line_end = lexed
+                .peek()
+                .is_none_or(|x| x.token_kind == TokenKind::Newline);
+            match name {
+                Some(name) if line_end => tokens.extend(self.include(file, &name)),
+                _ => {
+                    self.errors.push(Error::new(
+                        ErrorCode::InvalidInclude,
+                        "Expected a file name in quotes after `.include`".to_string(),
+                        token.span,
+                    ));
+                    while lexed
+                        .next_if(|x| x.token_kind != TokenKind::Newline)
+                        .is_some()
+                    {}
+                }
+            }
+        }
+        tokens
+    }
+
+    /// Tokens of the file named by the string token `name`, found in `from`.
+    fn include(&mut self, from: FileId, name: &Token) -> Vec<Token> {
+        let name_str = &name.lexeme[1..name.lexeme.len() - 1];
+        let path = match self.resolve(name_str, from) {
+            Some(path) => path,
+            None => {
+                self.errors.push(Error::new(
+                    ErrorCode::IncludeNotFound,
+                    format!("Cannot find included file `{}`", name_str),
+                    name.span,
+                ));
+                return vec![];
+            }
+        };
+
+        let canonical = canonical(&path);
+        if let Some(i) = self.stack.iter().position(|x| *x == canonical) {
+            let cycle = self.stack[i..]
+                .iter()
+                .chain([&canonical])
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>();
+            self.errors.push(Error::new(
+                ErrorCode::IncludeCycle,
+                format!("Include cycle: {}", cycle.join(" -> ")),
+                name.span,
+            ));
+            return vec![];
+        }
+
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                self.errors.push(Error::new(
+                    ErrorCode::IncludeNotFound,
+                    format!("Cannot read `{}`: {}", path.display(), e),
+                    name.span,
+                ));
+                return vec![];
+            }
+        };
+        let file = self.sources.add(path, source);
+        self.stack.push(canonical);
+        let tokens = self.tokens(file);
+        self.stack.pop();
+        tokens
+    }
+
+    /// Looks `name` up next to `from`, then in the search paths.
+    fn resolve(&self, name: &str, from: FileId) -> Option<PathBuf> {
+        let dir = self.sources.files()[from]
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        std::iter::once(dir)
+            .chain(self.search_paths.iter().cloned())
+            .map(|x| x.join(name))
+            .find(|x| x.is_file())
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::Span;
+
+    /// A fresh directory under the system temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("popper_asm_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = temp_dir("include");
+        let lib = dir.join("lib");
+        std::fs::create_dir_all(&lib).unwrap();
+        std::fs::write(lib.join("consts.popasm"), ".equ SIZE, 4\n").unwrap();
+        std::fs::write(
+            dir.join("util.popasm"),
+            ".include \"consts.popasm\"\nutil:\n    ret\n",
+        )
+        .unwrap();
+
+        let main = dir.join("main.popasm");
+        let source = ".include \"util.popasm\"\nmain:\n    mov r1, SIZE\n".to_string();
+        let mut loader = Loader::new(vec![lib.clone()]);
+        let tokens = loader.load(&main, source).unwrap();
+
+        let lexemes = tokens.iter().map(|x| x.lexeme.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            lexemes,
+            [
+                ".equ", "SIZE", ",", "4", "\n", "\n", "util", ":", "\n", "ret", "\n", "\n", "main",
+                ":", "\n", "mov", "r1", ",", "SIZE", "\n"
+            ]
+        );
+        assert_eq!(loader.sources().files().len(), 3);
+        assert_eq!(loader.sources().files()[2].path, lib.join("consts.popasm"));
+        assert_eq!(tokens[0].span, Span::new(0, 4).with_file(2));
+        assert_eq!(tokens[12].span.file(), 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = temp_dir("include_errors");
+        std::fs::write(dir.join("a.popasm"), ".include \"b.popasm\"\n").unwrap();
+        std::fs::write(dir.join("b.popasm"), "\n.include \"a.popasm\"\n").unwrap();
+
+        let source = ".include \"a.popasm\"\n.include missing\n.include \"missing.popasm\"\n";
+        let mut loader = Loader::new(vec![]);
+        let errors = loader
+            .load(&dir.join("main.popasm"), source.to_string())
+            .unwrap_err();
+
+        let a = canonical(&dir.join("a.popasm")).display().to_string();
+        let b = canonical(&dir.join("b.popasm")).display().to_string();
+        let messages = errors.iter().map(|x| x.message.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                format!("Include cycle: {} -> {} -> {}", a, b, a),
+                "Expected a file name in quotes after `.include`".to_string(),
+                "Cannot find included file `missing.popasm`".to_string(),
+            ]
+        );
+        assert_eq!(errors[0].span, Span::new(10, 20).with_file(2));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
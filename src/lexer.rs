@@ -1,4 +1,6 @@
-use crate::span::Span;
+use crate::error_code::ErrorCode;
+use crate::parser::Error;
+use crate::span::{FileId, Span};
 
 // token for the assembler
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -10,8 +12,17 @@ pub enum TokenKind {
     Hashtag,
     Comma,
     Dollar,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    LeftParen,
+    RightParen,
     Eof,
     Newline,
+    /// `.byte`, `.string`, ...: a dot directly followed by a name.
+    Directive,
 }
 
 #[derive(Clone, Debug)]
@@ -19,15 +30,31 @@ pub struct Token {
     pub token_kind: TokenKind,
     pub lexeme: String,
     pub span: Span,
+    /// For a token produced by a macro expansion, the span of the call;
+    /// `span` then points into the macro definition.
+    pub call_site: Option<Span>,
+}
+
+/// A `;` or `//` line comment. Comments are not tokens, but are kept
+/// so that tools can put them back into the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    /// The comment text, including its `;` or `//` marker.
+    pub text: String,
+    pub span: Span,
 }
 
 #[derive(Clone)]
 pub struct Lexer<'a> {
     pub source: &'a str,
     pub tokens: Vec<Token>,
+    pub comments: Vec<Comment>,
+    pub errors: Vec<Error>,
     pub start: usize,
     pub current: usize,
     pub line: usize,
+    /// File id given to every span.
+    pub file: FileId,
 }
 
 impl<'a> Lexer<'a> {
@@ -35,17 +62,33 @@ impl<'a> Lexer<'a> {
         Self {
             source,
             tokens: vec![],
+            comments: vec![],
+            errors: vec![],
             start: 0,
             current: 0,
             line: 1,
+            file: 0,
         }
     }
 
-    pub fn scan_tokens(&mut self) {
+    pub fn with_file(mut self, file: FileId) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// Scans the whole source. Bad characters are skipped and reported,
+    /// so every lexing error comes back from a single call.
+    pub fn scan_tokens(&mut self) -> Result<(), Vec<Error>> {
         while !self.is_at_end() {
             self.start = self.current;
             self.scan_token();
         }
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors.clone())
+        }
     }
 
     fn scan_token(&mut self) {
@@ -56,14 +99,39 @@ impl<'a> Lexer<'a> {
             ',' => self.add_token(TokenKind::Comma),
             '$' => self.add_token(TokenKind::Dollar),
             '"' => self.string(),
+            '.' if self.peek().is_ascii_alphabetic() => self.directive(),
+            ';' => self.comment(),
+            '/' if self.peek() == '/' => self.comment(),
+            '+' => self.add_token(TokenKind::Plus),
+            '-' => self.add_token(TokenKind::Minus),
+            '*' => self.add_token(TokenKind::Star),
+            '/' => self.add_token(TokenKind::Slash),
+            '%' => self.add_token(TokenKind::Percent),
+            '(' => self.add_token(TokenKind::LeftParen),
+            ')' => self.add_token(TokenKind::RightParen),
             '0'..='9' => self.number(),
             'a'..='z' | 'A'..='Z' | '_' => self.identifier(),
             '\n' => {
+                self.add_token(TokenKind::Newline);
                 self.line += 1;
             }
             ' ' | '\r' | '\t' => (),
-            e => panic!("Unexpected character: {}", e),
+            e => self.errors.push(Error::new(
+                ErrorCode::UnexpectedCharacter,
+                format!("Unexpected character {:?}", e),
+                self.span(self.start, self.current),
+            )),
+        }
+    }
+
+    fn comment(&mut self) {
+        while self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
         }
+
+        let text = self.source[self.start..self.current].trim_end().to_string();
+        let span = self.span(self.start, self.start + text.len());
+        self.comments.push(Comment { text, span });
     }
 
     fn identifier(&mut self) {
@@ -74,6 +142,14 @@ impl<'a> Lexer<'a> {
         self.add_token(TokenKind::Ident);
     }
 
+    fn directive(&mut self) {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
+
+        self.add_token(TokenKind::Directive);
+    }
+
     fn number(&mut self) {
         while self.peek().is_ascii_digit() {
             self.advance();
@@ -94,11 +170,20 @@ impl<'a> Lexer<'a> {
             if self.peek() == '\n' {
                 self.line += 1;
             }
+            // an escaped quote does not end the string
+            if self.peek() == '\\' && self.peek_next() != '\0' {
+                self.advance();
+            }
             self.advance();
         }
 
         if self.is_at_end() {
-            panic!("Unterminated string");
+            self.errors.push(Error::new(
+                ErrorCode::UnterminatedString,
+                "Unterminated string".to_string(),
+                self.span(self.start, self.current),
+            ));
+            return;
         }
 
         self.advance();
@@ -110,31 +195,33 @@ impl<'a> Lexer<'a> {
 
     fn add_token(&mut self, token_kind: TokenKind) {
         let lexeme = self.source[self.start..self.current].to_string();
-        let span = Span::new(self.start, self.current);
+        let span = self.span(self.start, self.current);
         self.tokens.push(Token {
             token_kind,
             lexeme,
             span,
+            call_site: None,
         });
     }
 
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span::new(start, end).with_file(self.file)
+    }
+
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        let c = self.peek();
+        self.current += c.len_utf8();
+        c
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current).unwrap()
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current + 1).unwrap()
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        chars.next().unwrap_or('\0')
     }
 
     fn is_at_end(&self) -> bool {
@@ -144,6 +231,14 @@ impl<'a> Lexer<'a> {
     pub fn get_tokens(&self) -> Vec<Token> {
         self.tokens.clone()
     }
+
+    pub fn get_errors(&self) -> Vec<Error> {
+        self.errors.clone()
+    }
+
+    pub fn get_comments(&self) -> Vec<Comment> {
+        self.comments.clone()
+    }
 }
 
 #[cfg(test)]
@@ -153,7 +248,7 @@ mod tests {
     #[test]
     fn test_ident() {
         let mut lexer = Lexer::new("mov");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].token_kind, TokenKind::Ident);
         assert_eq!(tokens[0].lexeme, "mov");
@@ -162,7 +257,7 @@ mod tests {
     #[test]
     fn test_int() {
         let mut lexer = Lexer::new("123");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].token_kind, TokenKind::Int);
         assert_eq!(tokens[0].lexeme, "123");
@@ -171,16 +266,56 @@ mod tests {
     #[test]
     fn test_string() {
         let mut lexer = Lexer::new("\"hello\"");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].token_kind, TokenKind::String);
         assert_eq!(tokens[0].lexeme, "\"hello\"");
     }
 
+    #[test]
+    fn test_directive() {
+        let mut lexer = Lexer::new(".string \"say \\\"hi\\\"\"");
+        lexer.scan_tokens().unwrap();
+        let tokens = lexer.get_tokens();
+        assert_eq!(tokens[0].token_kind, TokenKind::Directive);
+        assert_eq!(tokens[0].lexeme, ".string");
+        assert_eq!(tokens[1].token_kind, TokenKind::String);
+        assert_eq!(tokens[1].lexeme, "\"say \\\"hi\\\"\"");
+    }
+
+    #[test]
+    fn test_operators() {
+        let mut lexer = Lexer::new("-(1+2)*3/4%5 // done");
+        lexer.scan_tokens().unwrap();
+        let kinds = lexer
+            .get_tokens()
+            .iter()
+            .map(|x| x.token_kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::Minus,
+                TokenKind::LeftParen,
+                TokenKind::Int,
+                TokenKind::Plus,
+                TokenKind::Int,
+                TokenKind::RightParen,
+                TokenKind::Star,
+                TokenKind::Int,
+                TokenKind::Slash,
+                TokenKind::Int,
+                TokenKind::Percent,
+                TokenKind::Int,
+            ]
+        );
+        assert_eq!(lexer.get_comments()[0].text, "// done");
+    }
+
     #[test]
     fn test_colon() {
         let mut lexer = Lexer::new(":");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].token_kind, TokenKind::Colon);
         assert_eq!(tokens[0].lexeme, ":");
@@ -189,7 +324,7 @@ mod tests {
     #[test]
     fn test_hashtag() {
         let mut lexer = Lexer::new("#");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].token_kind, TokenKind::Hashtag);
         assert_eq!(tokens[0].lexeme, "#");
@@ -198,7 +333,7 @@ mod tests {
     #[test]
     fn test_comma() {
         let mut lexer = Lexer::new(",");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].token_kind, TokenKind::Comma);
         assert_eq!(tokens[0].lexeme, ",");
@@ -207,7 +342,7 @@ mod tests {
     #[test]
     fn test_all() {
         let mut lexer = Lexer::new("mov 123 : # , \"hello\"");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].token_kind, TokenKind::Ident);
         assert_eq!(tokens[0].lexeme, "mov");
@@ -226,7 +361,7 @@ mod tests {
     #[test]
     fn test_span() {
         let mut lexer = Lexer::new("mov 123 : # , \"hello\"");
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].span, Span::new(0, 3));
         assert_eq!(tokens[1].span, Span::new(4, 7));
@@ -240,7 +375,7 @@ mod tests {
     fn test_extract_span() {
         let source = "mov 123 : # , \"hello\"";
         let mut lexer = Lexer::new(source);
-        lexer.scan_tokens();
+        lexer.scan_tokens().unwrap();
         let tokens = lexer.get_tokens();
         assert_eq!(tokens[0].span.extract_from_str(source), "mov");
         assert_eq!(tokens[1].span.extract_from_str(source), "123");
@@ -249,4 +384,48 @@ mod tests {
         assert_eq!(tokens[4].span.extract_from_str(source), ",");
         assert_eq!(tokens[5].span.extract_from_str(source), "\"hello\"");
     }
+
+    #[test]
+    fn test_comments() {
+        let source = "; header\nmov r1, 2 ; set r1\n// état\nadd r1, r1// twice\n";
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let lexemes = lexer
+            .get_tokens()
+            .iter()
+            .map(|x| x.lexeme.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lexemes,
+            ["\n", "mov", "r1", ",", "2", "\n", "\n", "add", "r1", ",", "r1", "\n"]
+        );
+
+        let comments = lexer.get_comments();
+        let texts = comments.iter().map(|x| x.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, ["; header", "; set r1", "// état", "// twice"]);
+        for comment in comments {
+            assert_eq!(comment.span.extract_from_str(source), comment.text);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let source = "main:\n    mov r1, 2 @\n    mov r2, ~1\n    . string \"oops";
+        let mut lexer = Lexer::new(source);
+        let errors = lexer.scan_tokens().unwrap_err();
+        let reported = errors
+            .iter()
+            .map(|x| (x.message.as_str(), x.span.extract_from_str(source)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reported,
+            [
+                ("Unexpected character '@'", "@"),
+                ("Unexpected character '~'", "~"),
+                ("Unexpected character '.'", "."),
+                ("Unterminated string", "\"oops"),
+            ]
+        );
+        assert_eq!(lexer.get_tokens()[11].lexeme, "1");
+    }
 }
@@ -1,14 +1,28 @@
 #![allow(non_snake_case)]
 
 pub mod ast;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod emulator;
+pub mod error_code;
+pub mod eval;
+pub mod formatter;
+pub mod include;
 pub mod lexer;
+pub mod lint;
+pub mod listing;
+pub mod lsp;
 pub mod machine_code;
+pub mod macros;
+pub mod map;
 pub mod parser;
+pub mod source_map;
 pub mod span;
 
-#[derive(PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
 pub enum Mode {
     Debug,
+    #[default]
     Release,
 }
 
@@ -22,20 +36,159 @@ impl Mode {
     }
 }
 
-pub static MODE: Mode = Mode::Release;
+/// Settings for one assembler invocation.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct AssemblerOptions {
+    /// In `Mode::Debug` the text output formats put a space between fields.
+    pub mode: Mode,
+    pub format: OutputFormat,
+    /// Directories searched, in order, for `.include`d files that are not
+    /// next to the including file.
+    pub include_paths: Vec<std::path::PathBuf>,
+    /// Levels of the lints run by `lint::lint`.
+    pub lints: lint::LintLevels,
+}
+
+impl AssemblerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
 
-pub fn compile_file_into_file(file_name: &str, output_file_name: &str) {
-    let body = std::fs::read_to_string(file_name).unwrap();
-    let binary = compile_string(body.as_str());
-    std::fs::write(output_file_name, binary).unwrap();
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_include_path(mut self, path: std::path::PathBuf) -> Self {
+        self.include_paths.push(path);
+        self
+    }
+
+    pub fn with_lints(mut self, lints: lint::LintLevels) -> Self {
+        self.lints = lints;
+        self
+    }
 }
 
-pub fn compile_string(string: &str) -> String {
+/// How the assembled machine code is written out.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum OutputFormat {
+    /// Raw bytes, as produced by `MachineCodeInstruction::to_bytecode`.
+    #[default]
+    Bytes,
+    /// One line of `0`/`1` text per instruction.
+    Binary,
+    /// One line of hexadecimal text per instruction.
+    Hex,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" | "raw" => Ok(OutputFormat::Bytes),
+            "bin" | "binary" => Ok(OutputFormat::Binary),
+            "hex" => Ok(OutputFormat::Hex),
+            e => Err(format!(
+                "unknown output format `{}` (expected bytes, bin or hex)",
+                e
+            )),
+        }
+    }
+}
+
+/// Why `compile_file_into_file` failed.
+#[derive(Debug)]
+pub enum CompileFileError {
+    /// The input could not be read or the output could not be written.
+    Io(std::io::Error),
+    /// Every error found in the program.
+    Program(Vec<parser::Error>),
+}
+
+impl From<std::io::Error> for CompileFileError {
+    fn from(error: std::io::Error) -> Self {
+        CompileFileError::Io(error)
+    }
+}
+
+impl From<Vec<parser::Error>> for CompileFileError {
+    fn from(errors: Vec<parser::Error>) -> Self {
+        CompileFileError::Program(errors)
+    }
+}
+
+pub fn compile_file_into_file(
+    file_name: &str,
+    output_file_name: &str,
+    options: &AssemblerOptions,
+) -> Result<(), CompileFileError> {
+    let body = std::fs::read_to_string(file_name)?;
+    let mut loader = include::Loader::new(options.include_paths.clone());
+    let program = parse_file(&mut loader, std::path::Path::new(file_name), body)?;
+    let binary = compile_program(program, options)?.emit(options);
+    std::fs::write(output_file_name, binary)?;
+    Ok(())
+}
+
+/// Lexes and parses `string`, returning every lexer or parser error.
+pub fn parse_string(string: &str) -> Result<ast::Program, Vec<parser::Error>> {
     let mut lexer = lexer::Lexer::new(string);
-    lexer.scan_tokens();
+    lexer.scan_tokens()?;
     let mut parser = parser::Parser::new(lexer.get_tokens());
-    let out = parser.parse().unwrap();
-    let mut machine_code_compiler = machine_code::MachineCodeCompiler::new(out);
-    let m = machine_code_compiler.compile();
-    format!("{:b}", m)
-}
\ No newline at end of file
+    parser.parse()
+}
+
+/// Lexes and parses `source`, read from `path`, expanding its `.include`s
+/// with `loader`. Error spans carry the id of their file in `loader`.
+pub fn parse_file(
+    loader: &mut include::Loader,
+    path: &std::path::Path,
+    source: String,
+) -> Result<ast::Program, Vec<parser::Error>> {
+    let tokens = loader.load(path, source)?;
+    let mut parser = parser::Parser::new(tokens);
+    parser.parse()
+}
+
+pub fn compile_to_machine_code(
+    string: &str,
+    options: &AssemblerOptions,
+) -> Result<machine_code::MachineCode, Vec<parser::Error>> {
+    let out = parse_string(string)?;
+    compile_program(out, options)
+}
+
+pub fn compile_program(
+    program: ast::Program,
+    options: &AssemblerOptions,
+) -> Result<machine_code::MachineCode, Vec<parser::Error>> {
+    let mut machine_code_compiler =
+        machine_code::MachineCodeCompiler::with_options(program, options.clone());
+    machine_code_compiler
+        .compile()
+        .map_err(|errors| errors.into_iter().map(parser::Error::from).collect())
+}
+
+/// Assembles `string`. Lexer, parser and compiler errors are all returned as
+/// `parser::Error`s, ready for `Error::report`.
+pub fn compile_string(
+    string: &str,
+    options: &AssemblerOptions,
+) -> Result<Vec<u8>, Vec<parser::Error>> {
+    let m = compile_to_machine_code(string, options)?;
+    Ok(m.emit(options))
+}
+
+pub fn disassemble_bytes(bytes: &[u8]) -> Result<String, machine_code::DecodeError> {
+    let m = machine_code::MachineCode::from_bytecode(bytes)?;
+    let mut disassembler = disassembler::Disassembler::new(m);
+    let program = disassembler.disassemble()?;
+    Ok(program.to_string())
+}
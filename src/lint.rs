@@ -0,0 +1,290 @@
+//! Warnings about programs that assemble but are probably wrong.
+//!
+//! [`lint`] checks an `ast::Program` for every [`Lint`] whose [`Level`] is
+//! not `Allow`, and returns one diagnostic per finding: a warning, or an
+//! error if the lint is denied.
+
+use crate::ast::*;
+use crate::diagnostic::{Diagnostic, Severity};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Lint {
+    /// A label that no `call`, jump or `$label` refers to.
+    UnusedLabel,
+    /// Statements after a `ret` or `jmp` in the same label.
+    UnreachableCode,
+    /// `mov rX, rX`.
+    SelfMove,
+    /// `div x, 0`.
+    DivisionByZero,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [
+        Lint::UnusedLabel,
+        Lint::UnreachableCode,
+        Lint::SelfMove,
+        Lint::DivisionByZero,
+    ];
+
+    /// Name used on the command line and as the code of its diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedLabel => "unused-label",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::SelfMove => "self-move",
+            Lint::DivisionByZero => "division-by-zero",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Level {
+    /// Not checked.
+    Allow,
+    /// Reported as a warning.
+    #[default]
+    Warn,
+    /// Reported as an error.
+    Deny,
+}
+
+/// The level of every lint; those not set are `Level::Warn`.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct LintLevels {
+    levels: HashMap<Lint, Level>,
+}
+
+impl LintLevels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_level(mut self, lint: Lint, level: Level) -> Self {
+        self.levels.insert(lint, level);
+        self
+    }
+
+    /// Sets every lint to `level`.
+    pub fn with_all(mut self, level: Level) -> Self {
+        for lint in Lint::ALL {
+            self.levels.insert(lint, level);
+        }
+        self
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels.get(&lint).copied().unwrap_or_default()
+    }
+}
+
+/// Runs every lint that `levels` does not allow over `program`.
+pub fn lint(program: &Program, levels: &LintLevels) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        levels,
+        diagnostics: vec![],
+    };
+    linter.unused_labels(program);
+    for label in &program.labels {
+        linter.unreachable_code(label);
+        for statement in &label.program {
+            linter.statement(statement);
+        }
+    }
+    linter.diagnostics
+}
+
+struct Linter<'a> {
+    levels: &'a LintLevels,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, lint: Lint, diagnostic: Diagnostic) {
+        let severity = match self.levels.level(lint) {
+            Level::Allow => return,
+            Level::Warn => Severity::Warning,
+            Level::Deny => Severity::Error,
+        };
+        let mut diagnostic = diagnostic.with_code(lint.name().to_string());
+        diagnostic.severity = severity;
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// The first label is where the program starts, so it needs no reference.
+    fn unused_labels(&mut self, program: &Program) {
+        let mut used = HashSet::new();
+        for constant in &program.constants {
+            expr_labels(&constant.value, &mut used);
+        }
+        for statement in program.labels.iter().flat_map(|x| &x.program) {
+            command_labels(&statement.command, &mut used);
+        }
+
+        for label in program.labels.iter().skip(1) {
+            if !used.contains(&label.name) {
+                self.report(
+                    Lint::UnusedLabel,
+                    Diagnostic::warning(format!("Label `{}` is never used", label.name))
+                        .with_label(label.span, String::new()),
+                );
+            }
+        }
+    }
+
+    /// Reports the first statement after an unconditional `ret` or `jmp`.
+    /// The next label can still be reached by jumping to it.
+    fn unreachable_code(&mut self, label: &Label) {
+        let end = label
+            .program
+            .iter()
+            .position(|x| matches!(x.command, Command::Ret(_) | Command::Jump(_)));
+        let (end, next) = match end.map(|i| (&label.program[i], label.program.get(i + 1))) {
+            Some((end, Some(next))) => (end, next),
+            _ => return,
+        };
+
+        let mnemonic = match end.command {
+            Command::Ret(_) => "ret",
+            _ => "jmp",
+        };
+        let mut diagnostic = Diagnostic::warning("Unreachable code".to_string())
+            .with_label(next.span, "never runs".to_string())
+            .with_secondary_label(end.span, format!("nothing after this `{}` runs", mnemonic));
+        if let Some(call_site) = next.call_site {
+            diagnostic =
+                diagnostic.with_secondary_label(call_site, "in the macro called here".to_string());
+        }
+        self.report(Lint::UnreachableCode, diagnostic);
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        let (lint, message) = match &statement.command {
+            Command::Mov(Mov(
+                MemoryFetching::Register(to),
+                Expr::Memory(MemoryFetching::Register(from)),
+            )) if to == from => (
+                Lint::SelfMove,
+                format!("Moving `{}` into itself does nothing", to),
+            ),
+            Command::Div(Div(_, Expr::Int(0))) => {
+                (Lint::DivisionByZero, "Division by zero".to_string())
+            }
+            _ => return,
+        };
+
+        let mut diagnostic = Diagnostic::warning(message).with_label(statement.span, String::new());
+        if let Some(call_site) = statement.call_site {
+            diagnostic =
+                diagnostic.with_secondary_label(call_site, "in the macro called here".to_string());
+        }
+        self.report(lint, diagnostic);
+    }
+}
+
+/// Adds the labels referred to by `command` to `used`.
+fn command_labels(command: &Command, used: &mut HashSet<String>) {
+    let exprs = match command {
+        Command::Call(Call(label))
+        | Command::Jump(Jump(label))
+        | Command::CondJump(CondJump(_, label)) => {
+            used.insert(label.clone());
+            vec![]
+        }
+        Command::Mov(Mov(_, expr))
+        | Command::Add(Add(_, expr))
+        | Command::Sub(Sub(_, expr))
+        | Command::Div(Div(_, expr))
+        | Command::Cmp(Cmp(_, expr)) => vec![expr],
+        Command::Allow(Allow(a, b)) => vec![a, b],
+        Command::Data(Data::Byte(exprs) | Data::Word(exprs)) => exprs.iter().collect(),
+        Command::Data(Data::Zero(expr)) => vec![expr],
+        _ => vec![],
+    };
+    for expr in exprs {
+        expr_labels(expr, used);
+    }
+}
+
+fn expr_labels(expr: &Expr, used: &mut HashSet<String>) {
+    match expr {
+        Expr::Label(label) => {
+            used.insert(label.clone());
+        }
+        Expr::Unary(_, expr) => expr_labels(expr, used),
+        Expr::Binary(_, left, right) => {
+            expr_labels(left, used);
+            expr_labels(right, used);
+        }
+        Expr::Int(_) | Expr::Memory(_) | Expr::Const(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_string;
+
+    fn messages(source: &str, levels: &LintLevels) -> Vec<(Severity, String, String)> {
+        let program = parse_string(source).unwrap();
+        lint(&program, levels)
+            .into_iter()
+            .map(|x| {
+                let span = x.span().unwrap().extract_from_str(source).to_string();
+                (x.severity, x.code.unwrap(), span)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lint() {
+        let source = "\
+.equ TABLE, $table
+main:
+    mov r1, r1
+    mov r2, r1
+    call $print
+    jmp $main
+    div r2, 0
+    ret
+print:
+    div r1, 2
+    ret
+unused:
+    ret
+table:
+    .word $print
+";
+        let warnings = messages(source, &LintLevels::new());
+        let warning =
+            |code: &str, span: &str| (Severity::Warning, code.to_string(), span.to_string());
+        assert_eq!(
+            warnings,
+            [
+                warning("unused-label", "unused"),
+                warning("unreachable-code", "div r2, 0"),
+                warning("self-move", "mov r1, r1"),
+                warning("division-by-zero", "div r2, 0"),
+            ]
+        );
+
+        let levels = LintLevels::new()
+            .with_all(Level::Allow)
+            .with_level(Lint::SelfMove, Level::Deny);
+        assert_eq!(
+            messages(source, &levels),
+            [(
+                Severity::Error,
+                "self-move".to_string(),
+                "mov r1, r1".to_string()
+            )]
+        );
+        assert_eq!(Lint::from_name("unused-label"), Some(Lint::UnusedLabel));
+        assert_eq!(Lint::from_name("unused"), None);
+    }
+}
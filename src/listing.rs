@@ -0,0 +1,133 @@
+//! Assembly listings: the source next to the machine code it assembled to.
+//!
+//! [`listing`] writes every line of every source file with its line number,
+//! and beside it the address and encoded bytes of the instructions that
+//! line produced:
+//!
+//! ```text
+//!     1                                                          main:
+//!     2  00000001  01 00 00 00 11 01 01 00 00 00 02 07 00 00 00      mov r1, 7
+//! ```
+//!
+//! The address is that of the instruction itself. A macro call is
+//! followed by one line per instruction of its expansion. The listing ends
+//! with the address of every label.
+
+use crate::machine_code::{MachineCodeCompiler, MachineCodeInstruction, INSTRUCTION_SIZE};
+use crate::source_map::SourceMap;
+use crate::span::FileId;
+use std::collections::HashMap;
+
+/// Width of the encoded bytes of an instruction, as `01 00 ...`.
+const BYTES_WIDTH: usize = INSTRUCTION_SIZE * 3 - 1;
+
+/// The listing of the program compiled by `compiler`, read from `sources`.
+/// Instructions from statements outside `sources` are left out.
+pub fn listing(compiler: &MachineCodeCompiler, sources: &SourceMap) -> String {
+    let mut lines: HashMap<(FileId, usize), Vec<(u32, &MachineCodeInstruction)>> = HashMap::new();
+    for ((addr, statement), instr) in compiler
+        .statements()
+        .iter()
+        .zip(compiler.machine_code.instructions())
+    {
+        let site = statement.call_site.unwrap_or(statement.span);
+        let Some((line, _)) = sources.line_col(site) else {
+            continue;
+        };
+        lines
+            .entry((site.file(), line))
+            .or_default()
+            .push((*addr, instr));
+    }
+
+    let mut out = String::new();
+    for (id, file) in sources.files().iter().enumerate() {
+        if sources.files().len() > 1 {
+            out += &format!("; {}\n", file.path.display());
+        }
+        for line in 1..=file.line_count() {
+            let instrs = lines.get(&(id, line)).map_or(&[][..], |x| x.as_slice());
+            let first = instrs
+                .first()
+                .map_or(String::new(), |(addr, instr)| code(*addr, instr));
+            let text = format!(
+                "{:>5}  {:<width$}  {}",
+                line,
+                first,
+                file.line(line),
+                width = 10 + BYTES_WIDTH
+            );
+            out += text.trim_end();
+            out.push('\n');
+            for (addr, instr) in instrs.iter().skip(1) {
+                out += &format!("{:>5}  {}\n", "", code(*addr, instr));
+            }
+        }
+    }
+
+    let mut labels = compiler.labels().iter().collect::<Vec<_>>();
+    labels.sort_by_key(|(name, addr)| (**addr, name.as_str()));
+    out += "\nSymbols:\n";
+    for (name, addr) in labels {
+        out += &format!("{:08x}  {}\n", addr, name);
+    }
+    out
+}
+
+/// The address `addr` and encoded bytes of `instr`.
+fn code(addr: u32, instr: &MachineCodeInstruction) -> String {
+    let bytes = instr
+        .to_bytecode()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{:08x}  {}", addr, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_string;
+    use std::path::Path;
+
+    #[test]
+    fn test_listing() {
+        let source = "\
+.macro clear reg
+    mov reg, 0
+.endm
+main:
+    clear r1
+    call $print
+print:
+    mov r2, $msg ; address
+    ret
+msg:
+    .string \"hi\"
+";
+        let mut compiler = MachineCodeCompiler::new(parse_string(source).unwrap());
+        compiler.compile().unwrap();
+        let sources = SourceMap::new().with_file(Path::new("main.popasm"), source.to_string());
+
+        let expected =
+            "    1                                                          .macro clear reg
+    2                                                              mov reg, 0
+    3                                                          .endm
+    4                                                          main:
+    5  00000001  01 00 00 00 11 01 01 00 00 00 02 00 00 00 00      clear r1
+    6  00000002  01 00 00 00 17 03 03 00 00 00 00 00 00 00 00      call $print
+    7                                                          print:
+    8  00000003  03 00 00 00 11 01 02 00 00 00 03 00 80 00 00      mov r2, $msg ; address
+    9  00000004  03 00 00 00 21 00 00 00 00 00 00 00 00 00 00      ret
+   10                                                          msg:
+   11                                                              .string \"hi\"
+
+Symbols:
+00000001  main
+00000003  print
+00008000  msg
+";
+        assert_eq!(listing(&compiler, &sources), expected);
+    }
+}
@@ -0,0 +1,741 @@
+//! A Language Server Protocol server for PopperASM, run over stdio by the
+//! `popasm-lsp` binary.
+//!
+//! [`Server::run`] reads JSON-RPC messages framed by `Content-Length`
+//! headers and answers them. Documents are synced in full, and assembled
+//! again after every change. The server provides:
+//!
+//! - diagnostics from the lexer, parser, lints and compiler
+//! - go to definition and find references for labels, from `call $main`
+//!   or any other `$main`
+//! - hover with the address and encoded bytes of the instructions under the
+//!   cursor
+//! - completion of mnemonics at the start of a line, and of registers after
+//!   them
+//!
+//! Positions are line and UTF-16 character offsets from 0, as the protocol
+//! requires.
+
+use crate::ast::{Condition, Register};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::include::Loader;
+use crate::lexer::{Lexer, Token, TokenKind};
+use crate::lint::lint;
+use crate::machine_code::MachineCodeCompiler;
+use crate::parser::{Error, MNEMONICS};
+use crate::source_map::{SourceFile, SourceMap};
+use crate::span::Span;
+use crate::{parse_file, AssemblerOptions};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// `CompletionItemKind`s.
+const VARIABLE: u8 = 6;
+const KEYWORD: u8 = 14;
+
+/// The result of a request, or its error code and message.
+type Response = Result<Value, (i64, String)>;
+
+#[derive(Clone, Debug, Default)]
+pub struct Server {
+    /// Include paths and lint levels used to assemble documents.
+    options: AssemblerOptions,
+    /// Text of every open document, by URI.
+    documents: HashMap<String, String>,
+    shut_down: bool,
+    exited: bool,
+}
+
+impl Server {
+    pub fn new(options: AssemblerOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Whether `shutdown` was requested, in which case the process exits
+    /// with 0 after `exit`, and with 1 otherwise.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Answers the messages from `reader` on `writer`, until `exit` or the end
+    /// of the input.
+    pub fn run(&mut self, reader: &mut impl BufRead, writer: &mut impl Write) -> io::Result<()> {
+        while !self.exited {
+            let Some(body) = read_body(reader)? else {
+                break;
+            };
+            // a body that is not JSON is answered, and the next one read
+            let replies = match serde_json::from_slice(&body) {
+                Ok(message) => self.handle(&message),
+                Err(e) => vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": PARSE_ERROR, "message": format!("Invalid JSON: {}", e)},
+                })],
+            };
+            for reply in replies {
+                write_message(writer, &reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one message, returning the response and notifications to
+    /// send back.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let Some(method) = message["method"].as_str() else {
+            // a response to a request of ours, which we never send
+            return vec![];
+        };
+        let params = &message["params"];
+        let Some(id) = message.get("id") else {
+            return self.notification(method, params);
+        };
+
+        let result = match method {
+            _ if self.shut_down => Err((INVALID_REQUEST, "The server is shut down".to_string())),
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method `{}`", method))),
+        };
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        vec![response]
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // with full sync, the last change holds the whole text
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|x| x.last())
+                .and_then(|x| x["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, vec![])];
+            }
+            "exit" => {
+                self.exited = true;
+                None
+            }
+            _ => None,
+        };
+        let Some(text) = text else {
+            return vec![];
+        };
+
+        self.documents.insert(uri.clone(), text.to_string());
+        let analysis = Analysis::new(&uri, text, &self.options);
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .filter_map(|x| analysis.diagnostic(x))
+            .collect();
+        vec![publish_diagnostics(&uri, diagnostics)]
+    }
+
+    /// The URI and text of the document in `params`, and the byte offset of
+    /// its `position`.
+    fn document<'a>(
+        &'a self,
+        params: &'a Value,
+    ) -> Result<(&'a str, &'a str, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("`{}` is not open", uri)))?;
+        let position = &params["position"];
+        let line = position["line"].as_u64().unwrap_or_default() as usize;
+        let character = position["character"].as_u64().unwrap_or_default() as usize;
+        Ok((uri, text, offset(text, line, character)))
+    }
+
+    fn definition(&self, params: &Value) -> Response {
+        let (uri, text, offset) = self.document(params)?;
+        let analysis = Analysis::new(uri, text, &self.options);
+        let definition = analysis
+            .label_at(offset)
+            .and_then(|name| analysis.definition(name));
+        Ok(definition.map_or(Value::Null, |x| analysis.location(x)))
+    }
+
+    fn references(&self, params: &Value) -> Response {
+        let (uri, text, offset) = self.document(params)?;
+        let analysis = Analysis::new(uri, text, &self.options);
+        let Some(name) = analysis.label_at(offset) else {
+            return Ok(Value::Null);
+        };
+
+        let mut spans = analysis.references(name);
+        if params["context"]["includeDeclaration"].as_bool() == Some(true) {
+            spans.splice(0..0, analysis.definition(name));
+        }
+        Ok(spans.into_iter().map(|x| analysis.location(x)).collect())
+    }
+
+    /// The instructions of the statement or macro call under the cursor.
+    fn hover(&self, params: &Value) -> Response {
+        let (uri, text, offset) = self.document(params)?;
+        let analysis = Analysis::new(uri, text, &self.options);
+        let Some(compiler) = &analysis.compiler else {
+            return Ok(Value::Null);
+        };
+
+        let instructions = compiler
+            .statements()
+            .iter()
+            .zip(compiler.machine_code.instructions())
+            .filter(|((_, statement), _)| {
+                let site = statement.call_site.unwrap_or(statement.span);
+                site.file() == 0 && site.start() <= offset && offset <= site.end()
+            })
+            .map(|((addr, statement), instr)| {
+                let bytes = instr
+                    .to_bytecode()
+                    .iter()
+                    .map(|x| format!("{:02x}", x))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!("{:<4}{}\n    {}", format!("#{}", addr), statement, bytes)
+            })
+            .collect::<Vec<_>>();
+        if instructions.is_empty() {
+            return Ok(Value::Null);
+        }
+        Ok(json!({
+            "contents": {
+                "kind": "markdown",
+                "value": format!("```\n{}\n```", instructions.join("\n")),
+            }
+        }))
+    }
+
+    /// Mnemonics where the first word of a statement goes, registers after it.
+    fn completion(&self, params: &Value) -> Response {
+        let (_, text, offset) = self.document(params)?;
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let before = &text[line_start..offset];
+        let before = before.split_once(':').map_or(before, |(_, rest)| rest);
+
+        let items = if before.trim_start().contains(char::is_whitespace) {
+            (1..=16)
+                .map(|x| json!({"label": Register::from(x).to_string(), "kind": VARIABLE}))
+                .collect()
+        } else {
+            MNEMONICS
+                .into_iter()
+                .chain(Condition::ALL.iter().map(|x| x.mnemonic()))
+                .map(|x| json!({"label": x, "kind": KEYWORD}))
+                .collect()
+        };
+        Ok(Value::Array(items))
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            // full sync
+            "textDocumentSync": 1,
+            "definitionProvider": true,
+            "referencesProvider": true,
+            "hoverProvider": true,
+            "completionProvider": {},
+        },
+        "serverInfo": {"name": "popasm-lsp", "version": env!("CARGO_PKG_VERSION")},
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+/// One version of a document, assembled. The document is file 0 of
+/// `sources`.
+struct Analysis {
+    uri: String,
+    sources: SourceMap,
+    /// Tokens of the document alone, kept even if it does not lex or parse.
+    tokens: Vec<Token>,
+    /// Set if the document parsed, after compiling it.
+    compiler: Option<MachineCodeCompiler>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    fn new(uri: &str, text: &str, options: &AssemblerOptions) -> Self {
+        let mut lexer = Lexer::new(text);
+        let _ = lexer.scan_tokens();
+
+        let mut loader = Loader::new(options.include_paths.clone());
+        let mut diagnostics = vec![];
+        let compiler = match parse_file(&mut loader, &uri_to_path(uri), text.to_string()) {
+            Ok(program) => {
+                diagnostics.extend(lint(&program, &options.lints));
+                let mut compiler = MachineCodeCompiler::with_options(program, options.clone());
+                if let Err(errors) = compiler.compile() {
+                    diagnostics.extend(
+                        errors
+                            .into_iter()
+                            .map(|x| Diagnostic::from(&Error::from(x))),
+                    );
+                }
+                Some(compiler)
+            }
+            Err(errors) => {
+                diagnostics.extend(errors.iter().map(Diagnostic::from));
+                None
+            }
+        };
+
+        Self {
+            uri: uri.to_string(),
+            sources: loader.into_sources(),
+            tokens: lexer.get_tokens(),
+            compiler,
+            diagnostics,
+        }
+    }
+
+    /// Name of the label defined or referred to by the token at `offset`.
+    fn label_at(&self, offset: usize) -> Option<&str> {
+        let i = self.tokens.iter().position(|x| {
+            x.token_kind == TokenKind::Ident && x.span.start() <= offset && offset <= x.span.end()
+        })?;
+        (self.is_definition(i) || self.is_reference(i)).then(|| self.tokens[i].lexeme.as_str())
+    }
+
+    /// Whether token `i` is a label name followed by `:` at the start of a
+    /// line.
+    fn is_definition(&self, i: usize) -> bool {
+        let at_line_start = i == 0 || self.tokens[i - 1].token_kind == TokenKind::Newline;
+        self.tokens[i].token_kind == TokenKind::Ident
+            && at_line_start
+            && self
+                .tokens
+                .get(i + 1)
+                .is_some_and(|x| x.token_kind == TokenKind::Colon)
+    }
+
+    /// Whether token `i` is a label name after `$`, as in `call $main`.
+    fn is_reference(&self, i: usize) -> bool {
+        self.tokens[i].token_kind == TokenKind::Ident
+            && i.checked_sub(1)
+                .is_some_and(|i| self.tokens[i].token_kind == TokenKind::Dollar)
+    }
+
+    /// Where the label `name` is defined, possibly in an included file. If
+    /// the document does not parse, only its own labels are found.
+    fn definition(&self, name: &str) -> Option<Span> {
+        let parsed = self.compiler.as_ref().and_then(|x| {
+            x.program
+                .labels
+                .iter()
+                .find(|x| x.name == name)
+                .map(|x| x.span)
+        });
+        parsed.or_else(|| {
+            (0..self.tokens.len())
+                .find(|i| self.is_definition(*i) && self.tokens[*i].lexeme == name)
+                .map(|i| self.tokens[i].span)
+        })
+    }
+
+    /// Every place the document refers to the label `name`.
+    fn references(&self, name: &str) -> Vec<Span> {
+        (0..self.tokens.len())
+            .filter(|i| self.is_reference(*i) && self.tokens[*i].lexeme == name)
+            .map(|i| self.tokens[i].span)
+            .collect()
+    }
+
+    fn location(&self, span: Span) -> Value {
+        let uri = match self.sources.file(span.file()) {
+            Some(file) if span.file() != 0 => path_to_uri(&file.path),
+            _ => self.uri.clone(),
+        };
+        json!({"uri": uri, "range": self.range(span)})
+    }
+
+    fn range(&self, span: Span) -> Value {
+        let Some(file) = self.sources.file_of(span) else {
+            let start = json!({"line": 0, "character": 0});
+            return json!({"start": start, "end": start});
+        };
+        json!({
+            "start": position(file, span.start()),
+            "end": position(file, span.end()),
+        })
+    }
+
+    /// `diagnostic` as an LSP diagnostic of the document, at its primary
+    /// label or, for a problem in another file, at its first label in the
+    /// document. `None` if it has no label in the document.
+    fn diagnostic(&self, diagnostic: &Diagnostic) -> Option<Value> {
+        let span = std::iter::once(diagnostic.span()?)
+            .chain(diagnostic.labels.iter().map(|x| x.span))
+            .find(|x| x.file() == 0)?;
+        let message = std::iter::once(diagnostic.message.clone())
+            .chain(diagnostic.notes.iter().map(|x| format!("note: {}", x)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let related = diagnostic
+            .labels
+            .iter()
+            .filter(|x| x.span != span)
+            .map(|x| json!({"location": self.location(x.span), "message": x.message}))
+            .collect::<Vec<_>>();
+        let severity = match diagnostic.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        };
+
+        Some(json!({
+            "range": self.range(span),
+            "severity": severity,
+            "code": diagnostic.code,
+            "source": "popasm",
+            "message": message,
+            "relatedInformation": related,
+        }))
+    }
+}
+
+/// The LSP position of the byte at `offset` in `file`.
+fn position(file: &SourceFile, offset: usize) -> Value {
+    let offset = offset.min(file.source.len());
+    let (line, _) = file.line_col(offset);
+    let start = file.line_range(line).start;
+    json!({
+        "line": line - 1,
+        "character": file.source[start..offset].encode_utf16().count(),
+    })
+}
+
+/// The byte offset of an LSP position in `text`. A position past the end of
+/// its line is at the end of the line.
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let start = text
+        .split_inclusive('\n')
+        .take(line)
+        .map(str::len)
+        .sum::<usize>();
+    let mut utf16 = 0;
+    for (i, c) in text[start..].char_indices() {
+        if utf16 >= character || c == '\n' || c == '\r' {
+            return start + i;
+        }
+        utf16 += c.len_utf16();
+    }
+    text.len()
+}
+
+/// The path of a `file://` URI. Other URIs are used as they are.
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri).as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < path.len() {
+        let escaped = path
+            .get(i + 1..i + 3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (path[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            _ => uri += &format!("%{:02X}", byte),
+        }
+    }
+    uri
+}
+
+/// Reads one message framed by a `Content-Length` header, or `None` at the
+/// end of the input.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    read_body(reader)?
+        .map(|body| serde_json::from_slice(&body))
+        .transpose()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the body of one message, not yet parsed, or `None` at the end of
+/// the input.
+pub fn read_body(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///project/main.popasm";
+
+    /// The result of the request `method`.
+    fn request(server: &mut Server, method: &str, params: Value) -> Value {
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        }));
+        assert_eq!(replies.len(), 1);
+        replies[0]["result"].clone()
+    }
+
+    /// The diagnostics published after the notification `method`.
+    fn notify(server: &mut Server, method: &str, params: Value) -> Value {
+        let replies = server.handle(&json!({"jsonrpc": "2.0", "method": method, "params": params}));
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        replies[0]["params"]["diagnostics"].clone()
+    }
+
+    fn at(line: usize, character: usize) -> Value {
+        json!({
+            "textDocument": {"uri": URI},
+            "position": {"line": line, "character": character},
+            "context": {"includeDeclaration": true},
+        })
+    }
+
+    fn location(line: usize, start: usize, end: usize) -> Value {
+        json!({
+            "uri": URI,
+            "range": {
+                "start": {"line": line, "character": start},
+                "end": {"line": line, "character": end},
+            },
+        })
+    }
+
+    #[test]
+    fn test_server() {
+        let source = "\
+.macro clear reg
+    mov reg, 0
+.endm
+main:
+    mov r1, r1
+    call $print
+    jmp $main
+print:
+    clear r2
+    ret
+";
+        let mut server = Server::new(AssemblerOptions::new());
+        let result = request(&mut server, "initialize", json!({}));
+        assert_eq!(result["capabilities"]["hoverProvider"], true);
+
+        let diagnostics = notify(
+            &mut server,
+            "textDocument/didOpen",
+            json!({"textDocument": {"uri": URI, "languageId": "popasm", "version": 1, "text": source}}),
+        );
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["code"], "self-move");
+        assert_eq!(diagnostics[0]["severity"], 2);
+        assert_eq!(diagnostics[0]["range"], location(4, 4, 14)["range"]);
+
+        let print = location(7, 0, 5);
+        assert_eq!(
+            request(&mut server, "textDocument/definition", at(5, 11)),
+            print
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/definition", at(6, 12)),
+            location(3, 0, 4)
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/definition", at(4, 9)),
+            Value::Null
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/references", at(7, 2)),
+            json!([print, location(5, 10, 15)])
+        );
+
+        let hover = request(&mut server, "textDocument/hover", at(8, 6));
+        assert_eq!(
+            hover["contents"]["value"],
+            "```\n#4  mov r2, 0\n    04 00 00 00 11 01 02 00 00 00 02 00 00 00 00\n```"
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/hover", at(3, 0)),
+            Value::Null
+        );
+
+        let labels = |x: Value| {
+            x.as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["label"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let mnemonics = labels(request(&mut server, "textDocument/completion", at(9, 4)));
+        assert!(mnemonics.contains(&"jle".to_string()));
+        assert!(!mnemonics.contains(&"r1".to_string()));
+        let registers = labels(request(&mut server, "textDocument/completion", at(4, 8)));
+        assert_eq!(registers.len(), 16);
+        assert_eq!(registers.last().unwrap(), "rbp");
+
+        let diagnostics = notify(
+            &mut server,
+            "textDocument/didChange",
+            json!({
+                "textDocument": {"uri": URI, "version": 2},
+                "contentChanges": [{"text": "main:\n    jmp $nowhere\n"}],
+            }),
+        );
+        assert_eq!(diagnostics[0]["code"], "E0018");
+        assert_eq!(diagnostics[0]["range"], location(1, 8, 16)["range"]);
+
+        assert_eq!(request(&mut server, "shutdown", Value::Null), Value::Null);
+        assert!(server
+            .handle(&json!({"jsonrpc": "2.0", "method": "exit"}))
+            .is_empty());
+        assert!(server.is_shut_down());
+    }
+
+    #[test]
+    fn test_truncated_document() {
+        // a half-typed last line is the usual state of a document being edited
+        let mut server = Server::new(AssemblerOptions::new());
+        for text in [
+            "main:\n    mov r1,",
+            "main: mov",
+            "main:\n    call",
+            "main:\n    .byte 1,",
+        ] {
+            let diagnostics = notify(
+                &mut server,
+                "textDocument/didOpen",
+                json!({"textDocument": {"uri": URI, "languageId": "popasm", "version": 1, "text": text}}),
+            );
+            assert_eq!(diagnostics[0]["code"], "E0003", "{:?}", text);
+        }
+
+        let completion = request(&mut server, "textDocument/completion", at(1, 10));
+        assert!(completion.as_array().is_some_and(|x| !x.is_empty()));
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut input = vec![];
+        write_message(
+            &mut input,
+            &json!({"jsonrpc": "2.0", "id": 7, "method": "initialize"}),
+        )
+        .unwrap();
+        input.extend_from_slice(b"Content-Length: 6\r\n\r\n{oops}");
+        write_message(
+            &mut input,
+            &json!({"jsonrpc": "2.0", "id": 8, "method": "unknown"}),
+        )
+        .unwrap();
+        write_message(&mut input, &json!({"jsonrpc": "2.0", "method": "exit"})).unwrap();
+
+        let mut output = vec![];
+        let mut server = Server::new(AssemblerOptions::new());
+        server.run(&mut input.as_slice(), &mut output).unwrap();
+        assert!(!server.is_shut_down());
+
+        let mut output = output.as_slice();
+        let initialized = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(initialized["id"], 7);
+        assert_eq!(initialized["result"]["serverInfo"]["name"], "popasm-lsp");
+        let malformed = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(malformed["id"], Value::Null);
+        assert_eq!(malformed["error"]["code"], PARSE_ERROR);
+        let unknown = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        assert!(read_message(&mut output).unwrap().is_none());
+
+        assert_eq!(
+            uri_to_path("file:///a%20b/c.popasm"),
+            Path::new("/a b/c.popasm")
+        );
+        assert_eq!(
+            path_to_uri(Path::new("/a b/c.popasm")),
+            "file:///a%20b/c.popasm"
+        );
+    }
+}
@@ -1,25 +1,92 @@
 use crate::ast::*;
-use crate::MODE;
-use std::collections::HashMap;
-use std::fmt::{Binary, LowerHex};
-
-pub static VOID: u8 = 0x0;
-pub static REG: u8 = 0x1;
-pub static INT: u8 = 0x2;
-pub static LABEL: u8 = 0x3;
-pub static MEM: u8 = 0x4;
-pub static MOV: u8 = 0x11;
-pub static ADD: u8 = 0x12;
-pub static SUB: u8 = 0x13;
-pub static MUL: u8 = 0x14;
-pub static DIV: u8 = 0x15;
-pub static POP: u8 = 0x16;
-pub static CALL: u8 = 0x17;
-pub static ALLOW: u8 = 0x18;
-pub static NOP: u8 = 0x19;
-pub static JUMP: u8 = 0x20;
-pub static RET: u8 = 0x21;
+use crate::error_code::ErrorCode;
+use crate::eval::Evaluator;
+use crate::parser::Error;
+use crate::span::Span;
+use crate::{AssemblerOptions, Mode, OutputFormat};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Binary, Display, LowerHex};
 
+pub const VOID: u8 = 0x0;
+pub const REG: u8 = 0x1;
+pub const INT: u8 = 0x2;
+pub const LABEL: u8 = 0x3;
+pub const MEM: u8 = 0x4;
+pub const MOV: u8 = 0x11;
+pub const ADD: u8 = 0x12;
+pub const SUB: u8 = 0x13;
+pub const MUL: u8 = 0x14;
+pub const DIV: u8 = 0x15;
+pub const POP: u8 = 0x16;
+pub const CALL: u8 = 0x17;
+pub const ALLOW: u8 = 0x18;
+pub const NOP: u8 = 0x19;
+pub const JUMP: u8 = 0x20;
+pub const RET: u8 = 0x21;
+pub const CMP: u8 = 0x22;
+pub const JE: u8 = 0x23;
+pub const JNE: u8 = 0x24;
+pub const JL: u8 = 0x25;
+pub const JG: u8 = 0x26;
+pub const JLE: u8 = 0x27;
+pub const JGE: u8 = 0x28;
+/// Not an instruction: a record holding up to 4 bytes of the data segment.
+/// See `MachineCodeInstruction::data_record`.
+pub const DATA: u8 = 0x30;
+
+/// Size in bytes of one instruction produced by `MachineCodeInstruction::to_bytecode`.
+pub const INSTRUCTION_SIZE: usize = 15;
+
+/// Memory address where the emulator loads the data segment, and so the
+/// address of the first data label.
+pub const DATA_START: u32 = 0x8000;
+
+/// Largest data segment in bytes, so that every data address fits in a `u32`.
+pub const DATA_LIMIT: u32 = u32::MAX - DATA_START;
+
+/// Number of data segment bytes held by one `DATA` record.
+pub const DATA_RECORD_SIZE: usize = 4;
+
+pub const OPCODES: [u8; 19] = [
+    MOV, ADD, SUB, MUL, DIV, POP, CALL, ALLOW, NOP, JUMP, RET, CMP, JE, JNE, JL, JG, JLE, JGE, DATA,
+];
+pub const OPERAND_TYPES: [u8; 5] = [VOID, REG, INT, LABEL, MEM];
+
+pub fn condition_opcode(cond: Condition) -> u8 {
+    match cond {
+        Condition::Equal => JE,
+        Condition::NotEqual => JNE,
+        Condition::Less => JL,
+        Condition::Greater => JG,
+        Condition::LessEqual => JLE,
+        Condition::GreaterEqual => JGE,
+    }
+}
+
+pub fn opcode_condition(opcode: u8) -> Option<Condition> {
+    Condition::ALL
+        .into_iter()
+        .find(|x| condition_opcode(*x) == opcode)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl DecodeError {
+    pub fn new(message: String, offset: usize) -> Self {
+        Self { message, offset }
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at byte {}: {}", self.offset, self.message)
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct MachineCodeInstruction {
@@ -50,8 +117,35 @@ impl MachineCodeInstruction {
         }
     }
 
-    pub fn binary_string(&self) -> String {
-        let space = if MODE.is_debug() { " " } else { "" };
+    /// The `NOP` that `MachineCodeCompiler::compile` appends after the last instruction.
+    pub fn end_marker() -> Self {
+        Self::new(
+            [0x1, 0x0, 0x0, 0x0],
+            NOP,
+            VOID,
+            Default::default(),
+            VOID,
+            Default::default(),
+        )
+    }
+
+    /// A `DATA` record loading `bytes` (at most `DATA_RECORD_SIZE`) at `addr`.
+    /// The bytes go in the first operand, their count in the second.
+    pub fn data_record(addr: u32, bytes: &[u8]) -> Self {
+        let mut operand1: [u8; 4] = Default::default();
+        operand1[..bytes.len()].copy_from_slice(bytes);
+        Self::new(
+            addr.to_le_bytes(),
+            DATA,
+            INT,
+            operand1,
+            INT,
+            (bytes.len() as u32).to_le_bytes(),
+        )
+    }
+
+    pub fn binary_string(&self, options: &AssemblerOptions) -> String {
+        let space = if options.mode.is_debug() { " " } else { "" };
         let instr_addr_bin = self
             .label
             .iter()
@@ -74,7 +168,7 @@ impl MachineCodeInstruction {
             .collect::<Vec<String>>()
             .join(space);
 
-        vec![
+        [
             instr_addr_bin,
             instr_bin,
             operand1_type_bin,
@@ -85,8 +179,8 @@ impl MachineCodeInstruction {
         .join(space)
     }
 
-    pub fn hex_string(&self) -> String {
-        let space = if MODE.is_debug() { " " } else { "" };
+    pub fn hex_string(&self, options: &AssemblerOptions) -> String {
+        let space = if options.mode.is_debug() { " " } else { "" };
         let instr_addr_mem_hex = self
             .label
             .iter()
@@ -111,7 +205,7 @@ impl MachineCodeInstruction {
             .collect::<Vec<String>>()
             .join(space);
 
-        vec![
+        [
             instr_addr_mem_hex,
             instr_hex,
             operand1_type_hex,
@@ -133,19 +227,67 @@ impl MachineCodeInstruction {
         bytecode
     }
 
-    pub fn from_bytecode(_bytecode: Vec<u8>) -> Self {
-        todo!()
+    pub fn from_bytecode(bytecode: &[u8]) -> Result<Self, DecodeError> {
+        if bytecode.len() != INSTRUCTION_SIZE {
+            return Err(DecodeError::new(
+                format!(
+                    "Expected {} bytes, found {}",
+                    INSTRUCTION_SIZE,
+                    bytecode.len()
+                ),
+                0,
+            ));
+        }
+
+        let instr = bytecode[4];
+        if !OPCODES.contains(&instr) {
+            return Err(DecodeError::new(
+                format!("Unknown opcode {:#04x}", instr),
+                4,
+            ));
+        }
+
+        for offset in [5, 10] {
+            if !OPERAND_TYPES.contains(&bytecode[offset]) {
+                return Err(DecodeError::new(
+                    format!("Unknown operand type {:#04x}", bytecode[offset]),
+                    offset,
+                ));
+            }
+        }
+
+        let mut label: [u8; 4] = Default::default();
+        let mut operand1: [u8; 4] = Default::default();
+        let mut operand2: [u8; 4] = Default::default();
+        label.copy_from_slice(&bytecode[0..4]);
+        operand1.copy_from_slice(&bytecode[6..10]);
+        operand2.copy_from_slice(&bytecode[11..15]);
+
+        Ok(Self::new(
+            label,
+            instr,
+            bytecode[5],
+            operand1,
+            bytecode[10],
+            operand2,
+        ))
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct MachineCode {
     code: Vec<MachineCodeInstruction>,
+    data: Vec<u8>,
 }
 
 impl MachineCode {
     pub fn new(code: Vec<MachineCodeInstruction>) -> Self {
-        Self { code }
+        Self { code, data: vec![] }
+    }
+
+    pub fn with_data(mut self, data: Vec<u8>) -> Self {
+        self.data = data;
+        self
     }
 
     pub fn push(&mut self, instr: MachineCodeInstruction) {
@@ -156,6 +298,10 @@ impl MachineCode {
         self.code.extend(instrs);
     }
 
+    pub fn extend_data(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
     pub fn last_mut(&mut self) -> Option<&mut MachineCodeInstruction> {
         self.code.last_mut()
     }
@@ -167,198 +313,544 @@ impl MachineCode {
             .count()
             != 0
     }
-}
 
-impl Binary for MachineCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    pub fn instructions(&self) -> &[MachineCodeInstruction] {
+        &self.code
+    }
+
+    /// The data segment, loaded at `DATA_START`.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The instructions, then the `DATA` records of the data segment.
+    pub fn records(&self) -> Vec<MachineCodeInstruction> {
+        let data = self
+            .data
+            .chunks(DATA_RECORD_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let addr = DATA_START + (i * DATA_RECORD_SIZE) as u32;
+                MachineCodeInstruction::data_record(addr, chunk)
+            });
+        self.code.iter().cloned().chain(data).collect()
+    }
+
+    pub fn to_bytecode(&self) -> Vec<u8> {
+        self.records()
+            .iter()
+            .flat_map(|x| x.to_bytecode())
+            .collect()
+    }
+
+    pub fn from_bytecode(bytecode: &[u8]) -> Result<Self, DecodeError> {
+        if !bytecode.len().is_multiple_of(INSTRUCTION_SIZE) {
+            return Err(DecodeError::new(
+                format!(
+                    "Truncated instruction: {} trailing bytes",
+                    bytecode.len() % INSTRUCTION_SIZE
+                ),
+                bytecode.len() - bytecode.len() % INSTRUCTION_SIZE,
+            ));
+        }
+
+        let mut machine_code = Self::new(vec![]);
+        for (i, chunk) in bytecode.chunks(INSTRUCTION_SIZE).enumerate() {
+            let offset = i * INSTRUCTION_SIZE;
+            let instr = MachineCodeInstruction::from_bytecode(chunk)
+                .map_err(|e| DecodeError::new(e.message, offset + e.offset))?;
+            if instr.instr == DATA {
+                machine_code.push_data_record(&instr, offset)?;
+            } else if machine_code.data.is_empty() {
+                machine_code.push(instr);
+            } else {
+                return Err(DecodeError::new(
+                    "Instruction after the data segment".to_string(),
+                    offset,
+                ));
+            }
+        }
+        Ok(machine_code)
+    }
+
+    /// Appends the bytes of a `DATA` record found at `offset`, checking that
+    /// it carries on from the previous one.
+    fn push_data_record(
+        &mut self,
+        record: &MachineCodeInstruction,
+        offset: usize,
+    ) -> Result<(), DecodeError> {
+        let expected = DATA_START as usize + self.data.len();
+        let addr = u32::from_le_bytes(record.label) as usize;
+        if addr != expected || !self.data.len().is_multiple_of(DATA_RECORD_SIZE) {
+            return Err(DecodeError::new(
+                format!("Data record at #{}, expected #{}", addr, expected),
+                offset,
+            ));
+        }
+        let len = u32::from_le_bytes(record.operand2) as usize;
+        if record.operand1_type != INT || record.operand2_type != INT || len == 0 || len > 4 {
+            return Err(DecodeError::new(
+                "Malformed data record".to_string(),
+                offset + 5,
+            ));
+        }
+        self.data.extend_from_slice(&record.operand1[..len]);
+        Ok(())
+    }
+
+    /// One line per record, as `MachineCodeInstruction::binary_string`.
+    pub fn binary_string(&self, options: &AssemblerOptions) -> String {
+        let mut string = String::new();
+
+        for byte in &self.records() {
+            string.push_str(&byte.binary_string(options));
+            string.push('\n');
+        }
+
+        string
+    }
+
+    /// One line per record, as `MachineCodeInstruction::hex_string`.
+    pub fn hex_string(&self, options: &AssemblerOptions) -> String {
         let mut string = String::new();
 
-        for byte in &self.code {
-            string.push_str(&byte.binary_string());
+        for byte in &self.records() {
+            string.push_str(&byte.hex_string(options));
             string.push('\n');
         }
 
-        write!(f, "{}", string)
+        string
+    }
+
+    pub fn emit(&self, options: &AssemblerOptions) -> Vec<u8> {
+        match options.format {
+            OutputFormat::Bytes => self.to_bytecode(),
+            OutputFormat::Binary => self.binary_string(options).into_bytes(),
+            OutputFormat::Hex => self.hex_string(options).into_bytes(),
+        }
     }
 }
 
+/// `{:b}` prints the release layout, `{:#b}` the spaced debug layout.
+impl Binary for MachineCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let options = AssemblerOptions::new().with_mode(fmt_mode(f));
+        write!(f, "{}", self.binary_string(&options))
+    }
+}
+
+/// `{:x}` prints the release layout, `{:#x}` the spaced debug layout.
 impl LowerHex for MachineCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut string = String::new();
+        let options = AssemblerOptions::new().with_mode(fmt_mode(f));
+        write!(f, "{}", self.hex_string(&options))
+    }
+}
 
-        for byte in &self.code {
-            string.push_str(&byte.hex_string());
-            string.push('\n');
+fn fmt_mode(f: &std::fmt::Formatter<'_>) -> Mode {
+    if f.alternate() {
+        Mode::Debug
+    } else {
+        Mode::Release
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileErrorKind {
+    UnknownLabel(String),
+    /// A label defined twice; holds the span of the first definition.
+    DuplicateLabel(String, Span),
+    InvalidOperand(String),
+    /// A label holding both data directives and instructions.
+    MixedLabel(String),
+    UnknownConstant(String),
+    /// A constant defined twice; holds the span of the first definition.
+    DuplicateConstant(String, Span),
+    RecursiveConstant(String),
+    InvalidExpression(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    pub span: Span,
+    /// See `Statement::call_site`.
+    pub call_site: Option<Span>,
+}
+
+impl CompileError {
+    pub fn new(kind: CompileErrorKind, span: Span) -> Self {
+        Self {
+            kind,
+            span,
+            call_site: None,
+        }
+    }
+
+    pub fn with_call_site(mut self, call_site: Option<Span>) -> Self {
+        self.call_site = call_site;
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match &self.kind {
+            CompileErrorKind::UnknownLabel(_) => ErrorCode::UnknownLabel,
+            CompileErrorKind::DuplicateLabel(..) => ErrorCode::DuplicateLabel,
+            CompileErrorKind::InvalidOperand(_) => ErrorCode::InvalidOperand,
+            CompileErrorKind::MixedLabel(_) => ErrorCode::MixedLabel,
+            CompileErrorKind::UnknownConstant(_) => ErrorCode::UnknownConstant,
+            CompileErrorKind::DuplicateConstant(..) => ErrorCode::DuplicateConstant,
+            CompileErrorKind::RecursiveConstant(_) => ErrorCode::RecursiveConstant,
+            CompileErrorKind::InvalidExpression(_) => ErrorCode::InvalidExpression,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match &self.kind {
+            CompileErrorKind::UnknownLabel(name) => format!("Unknown label `{}`", name),
+            CompileErrorKind::DuplicateLabel(name, _) => {
+                format!("Label `{}` is defined more than once", name)
+            }
+            CompileErrorKind::InvalidOperand(message) => message.clone(),
+            CompileErrorKind::MixedLabel(name) => {
+                format!("Label `{}` mixes data directives and instructions", name)
+            }
+            CompileErrorKind::UnknownConstant(name) => format!("Unknown constant `{}`", name),
+            CompileErrorKind::DuplicateConstant(name, _) => {
+                format!("Constant `{}` is defined more than once", name)
+            }
+            CompileErrorKind::RecursiveConstant(name) => {
+                format!("Constant `{}` is defined in terms of itself", name)
+            }
+            CompileErrorKind::InvalidExpression(message) => message.clone(),
         }
+    }
+}
 
-        write!(f, "{}", string)
+impl From<CompileError> for Error {
+    fn from(value: CompileError) -> Self {
+        Error::new(value.code(), value.message(), value.span).with_call_site(value.call_site)
     }
 }
 
+/// Where a label ended up, as written to map files.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    /// Number of instructions of a code label, or of bytes of a data label.
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolKind {
+    /// A label in the code, addressed by instruction from 1.
+    Code,
+    /// A label in the data segment, addressed by byte from `DATA_START`.
+    Data,
+}
+
+impl Display for SymbolKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymbolKind::Code => write!(f, "code"),
+            SymbolKind::Data => write!(f, "data"),
+        }
+    }
+}
+
+const NO_OPERAND: (u8, [u8; 4]) = (VOID, [0; 4]);
+
 #[derive(Clone, Debug)]
 pub struct MachineCodeCompiler {
     pub program: Program,
+    pub options: AssemblerOptions,
     pub machine_code: MachineCode,
     labels: HashMap<String, u32>,
+    data_labels: HashSet<String>,
+    constants: HashMap<String, Constant>,
     current_label: u32,
+    /// Address and statement of every instruction in `machine_code`.
+    statements: Vec<(u32, Statement)>,
+    symbols: Vec<Symbol>,
+    errors: Vec<CompileError>,
 }
 
 impl MachineCodeCompiler {
     pub fn new(program: Program) -> Self {
+        Self::with_options(program, AssemblerOptions::default())
+    }
+
+    pub fn with_options(program: Program, options: AssemblerOptions) -> Self {
         Self {
             program,
+            options,
             machine_code: MachineCode::new(vec![]),
             labels: HashMap::new(),
+            data_labels: HashSet::new(),
+            constants: HashMap::new(),
             current_label: 1,
+            statements: vec![],
+            symbols: vec![],
+            errors: vec![],
         }
     }
 
-    pub fn compile(&mut self) -> MachineCode {
+    /// Compiles the program. Every unknown label, duplicate label and invalid
+    /// operand is reported, not only the first one.
+    pub fn compile(&mut self) -> Result<MachineCode, Vec<CompileError>> {
+        self.setup_constants(self.program.constants.clone());
         self.setup_labels(self.program.labels.clone());
         self.labels_compiler(self.program.labels.clone());
-        self.machine_code.push(MachineCodeInstruction::new(
-            self.int_to_bytes(1),
-            NOP,
-            VOID,
-            Default::default(),
-            VOID,
-            Default::default(),
-        ));
-        self.machine_code.clone()
+        self.machine_code.push(MachineCodeInstruction::end_marker());
+
+        if self.errors.is_empty() {
+            Ok(self.machine_code.clone())
+        } else {
+            Err(self.errors.clone())
+        }
+    }
+
+    /// Compiles the program and writes it out in `self.options.format`.
+    pub fn assemble(&mut self) -> Result<Vec<u8>, Vec<CompileError>> {
+        let machine_code = self.compile()?;
+        Ok(machine_code.emit(&self.options))
     }
 
+    pub fn labels(&self) -> &HashMap<String, u32> {
+        &self.labels
+    }
+
+    /// Every label set up by `setup_labels`, in the order they are defined.
+    /// A label defined twice is only listed once.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// The address and source statement of each instruction of
+    /// `machine_code`, in the same order. A statement that failed to compile
+    /// has no instruction, but still takes up its address.
+    pub fn statements(&self) -> &[(u32, Statement)] {
+        &self.statements
+    }
+
+    pub fn setup_constants(&mut self, constants: Vec<Constant>) {
+        for constant in constants {
+            if let Some(first) = self.constants.get(&constant.name) {
+                self.errors.push(CompileError::new(
+                    CompileErrorKind::DuplicateConstant(constant.name.clone(), first.span),
+                    constant.span,
+                ));
+            } else {
+                self.constants.insert(constant.name.clone(), constant);
+            }
+        }
+    }
+
+    /// Evaluates operands once every label has its address.
+    pub fn evaluator(&self) -> Evaluator<'_> {
+        Evaluator::new(&self.constants, Some(&self.labels))
+    }
+
+    /// Gives every label its address: code labels count instructions from 1,
+    /// data labels count bytes from `DATA_START`. A data segment larger than
+    /// `DATA_LIMIT` is reported at the directive that does not fit.
     pub fn setup_labels(&mut self, labels: Vec<Label>) {
-        labels.iter().fold(1, move |acc, x| {
-            self.labels.insert(x.name.clone(), acc);
-            acc + x.program.len() as u32
-        });
+        let mut spans: HashMap<String, Span> = HashMap::new();
+        let mut code_addr = 1;
+        let mut data_len: u64 = 0;
+        for x in &labels {
+            let is_data = x.is_data();
+            let data_addr = DATA_START + data_len.min(DATA_LIMIT as u64) as u32;
+            let data_size = self.data_size(&x.program).min(DATA_LIMIT as u64) as u32;
+            if !is_data && x.program.iter().any(|x| x.command.is_data()) {
+                self.errors.push(CompileError::new(
+                    CompileErrorKind::MixedLabel(x.name.clone()),
+                    x.span,
+                ));
+            }
+
+            if let Some(first) = spans.get(&x.name) {
+                self.errors.push(CompileError::new(
+                    CompileErrorKind::DuplicateLabel(x.name.clone(), *first),
+                    x.span,
+                ));
+            } else if is_data {
+                spans.insert(x.name.clone(), x.span);
+                self.labels.insert(x.name.clone(), data_addr);
+                self.data_labels.insert(x.name.clone());
+                self.symbols.push(Symbol {
+                    name: x.name.clone(),
+                    address: data_addr,
+                    size: data_size,
+                    kind: SymbolKind::Data,
+                });
+            } else {
+                spans.insert(x.name.clone(), x.span);
+                self.labels.insert(x.name.clone(), code_addr);
+                self.symbols.push(Symbol {
+                    name: x.name.clone(),
+                    address: code_addr,
+                    size: code_len(x),
+                    kind: SymbolKind::Code,
+                });
+            }
+
+            if is_data {
+                for statement in &x.program {
+                    let size = self.data_size(std::slice::from_ref(statement));
+                    if data_len <= DATA_LIMIT as u64 && data_len + size > DATA_LIMIT as u64 {
+                        self.errors.push(
+                            CompileError::new(
+                                CompileErrorKind::InvalidOperand(format!(
+                                    "The data segment is larger than {} bytes",
+                                    DATA_LIMIT
+                                )),
+                                statement.span,
+                            )
+                            .with_call_site(statement.call_site),
+                        );
+                    }
+                    data_len += size;
+                }
+            } else {
+                code_addr += code_len(x);
+            }
+        }
     }
 
     pub fn labels_compiler(&mut self, labels: Vec<Label>) {
-        labels.iter().for_each(|x| {
-            self.current_label = self.labels.get(&x.name).cloned().unwrap();
-            self.command_compiler(x.program.clone());
-        })
-    }
-
-    pub fn command_compiler(&mut self, commands: Vec<Command>) {
-        for cmd in commands.clone() {
-            match cmd {
-                Command::Mov(mov) => {
-                    let (operand1_type, operand1) = self.memory_to_bytes(mov.0);
-                    let (operand2_type, operand2) = self.expr_to_bytes(mov.1);
-
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        MOV,
-                        operand1_type,
-                        operand1,
-                        operand2_type,
-                        operand2,
-                    ));
-                }
-                Command::Add(add) => {
-                    let (operand1_type, operand1) = self.memory_to_bytes(add.0);
-                    let (operand2_type, operand2) = self.expr_to_bytes(add.1);
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        ADD,
-                        operand1_type,
-                        operand1,
-                        operand2_type,
-                        operand2,
-                    ));
-                }
-                Command::Sub(sub) => {
-                    let (operand1_type, operand1) = self.memory_to_bytes(sub.0);
-                    let (operand2_type, operand2) = self.expr_to_bytes(sub.1);
-
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        SUB,
-                        operand1_type,
-                        operand1,
-                        operand2_type,
-                        operand2,
-                    ));
-                }
-                Command::Mul(mul) => {
-                    let (operand1_type, operand1) = self.memory_to_bytes(mul.0);
-
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        MUL,
-                        operand1_type,
-                        operand1,
-                        VOID,
-                        Default::default(),
-                    ));
-                }
-                Command::Div(div) => {
-                    let (operand1_type, operand1) = self.memory_to_bytes(div.0);
-                    let (operand2_type, operand2) = self.expr_to_bytes(div.1);
-
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        DIV,
-                        operand1_type,
-                        operand1,
-                        operand2_type,
-                        operand2,
-                    ));
-                }
-                Command::Pop(pop) => {
-                    let (operand1_type, operand1) = self.memory_to_bytes(pop.0);
-
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        POP,
-                        operand1_type,
-                        operand1,
-                        VOID,
-                        Default::default(),
-                    ));
-                }
-                Command::Call(call) => {
-                    let label = *self.labels.get(&call.0).unwrap();
-                    let n = self.int_to_bytes(label);
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        CALL,
-                        LABEL,
-                        n,
-                        VOID,
-                        Default::default(),
-                    ));
-                    self.current_label = label;
-                }
-                Command::Allow(allow) => {
-                    let (operand1_type, operand1) = self.expr_to_bytes(allow.0);
-                    let (operand2_type, operand2) = self.expr_to_bytes(allow.1);
-
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        ALLOW,
-                        operand1_type,
-                        operand1,
-                        operand2_type,
-                        operand2,
-                    ));
+        // a data segment too large to address is reported by `setup_labels`,
+        // and not built
+        let data_len: u64 = labels
+            .iter()
+            .filter(|x| x.is_data())
+            .map(|x| self.data_size(&x.program))
+            .sum();
+        labels.iter().fold(1, |acc, x| {
+            if x.is_data() {
+                if data_len <= DATA_LIMIT as u64 {
+                    self.data_compiler(x.program.clone());
                 }
-                Command::Ret(_) => {
-                    self.machine_code.push(MachineCodeInstruction::new(
-                        self.int_to_bytes(self.current_label),
-                        RET,
-                        VOID,
-                        Default::default(),
-                        VOID,
-                        Default::default(),
-                    ));
+                return acc;
+            }
+            self.current_label = acc;
+            self.command_compiler(x.program.clone());
+            acc + code_len(x)
+        });
+    }
+
+    pub fn data_compiler(&mut self, statements: Vec<Statement>) {
+        for statement in statements {
+            let Command::Data(data) = &statement.command else {
+                continue;
+            };
+            match self.data_to_bytes(data, &statement) {
+                Ok(bytes) => self.machine_code.extend_data(&bytes),
+                Err(e) => self.errors.push(e.with_call_site(statement.call_site)),
+            }
+        }
+    }
+
+    pub fn command_compiler(&mut self, statements: Vec<Statement>) {
+        let mut addr = self.current_label;
+        for statement in statements {
+            // directives in a code label are reported by `setup_labels`
+            if statement.command.is_data() {
+                continue;
+            }
+            match self.command_to_instruction(&statement) {
+                Ok(instr) => {
+                    self.machine_code.push(instr);
+                    self.statements.push((addr, statement));
                 }
+                Err(e) => self.errors.push(e.with_call_site(statement.call_site)),
             }
+            addr += 1;
         }
     }
 
+    /// Encodes `statement`. An invalid operand is reported at its own span.
+    pub fn command_to_instruction(
+        &self,
+        statement: &Statement,
+    ) -> Result<MachineCodeInstruction, CompileError> {
+        let span = |i| statement.operand_span(i);
+        let (instr, (operand1_type, operand1), (operand2_type, operand2)) =
+            match statement.command.clone() {
+                Command::Mov(mov) => (
+                    MOV,
+                    self.memory_to_bytes(mov.0, span(0))?,
+                    self.expr_to_bytes(mov.1, span(1))?,
+                ),
+                Command::Add(add) => (
+                    ADD,
+                    self.memory_to_bytes(add.0, span(0))?,
+                    self.expr_to_bytes(add.1, span(1))?,
+                ),
+                Command::Sub(sub) => (
+                    SUB,
+                    self.memory_to_bytes(sub.0, span(0))?,
+                    self.expr_to_bytes(sub.1, span(1))?,
+                ),
+                Command::Mul(mul) => (MUL, self.memory_to_bytes(mul.0, span(0))?, NO_OPERAND),
+                Command::Div(div) => (
+                    DIV,
+                    self.memory_to_bytes(div.0, span(0))?,
+                    self.expr_to_bytes(div.1, span(1))?,
+                ),
+                Command::Pop(pop) => (POP, self.memory_to_bytes(pop.0, span(0))?, NO_OPERAND),
+                Command::Call(call) => (
+                    CALL,
+                    self.code_label_to_bytes(&call.0, span(0))?,
+                    NO_OPERAND,
+                ),
+                Command::Allow(allow) => (
+                    ALLOW,
+                    self.expr_to_bytes(allow.0, span(0))?,
+                    self.expr_to_bytes(allow.1, span(1))?,
+                ),
+                Command::Ret(_) => (RET, NO_OPERAND, NO_OPERAND),
+                Command::Nop(_) => (NOP, NO_OPERAND, NO_OPERAND),
+                Command::Jump(jump) => (
+                    JUMP,
+                    self.code_label_to_bytes(&jump.0, span(0))?,
+                    NO_OPERAND,
+                ),
+                Command::Cmp(cmp) => (
+                    CMP,
+                    self.memory_to_bytes(cmp.0, span(0))?,
+                    self.expr_to_bytes(cmp.1, span(1))?,
+                ),
+                Command::CondJump(jump) => (
+                    condition_opcode(jump.0),
+                    self.code_label_to_bytes(&jump.1, span(0))?,
+                    NO_OPERAND,
+                ),
+                Command::Data(data) => {
+                    return Err(CompileError::new(
+                        CompileErrorKind::InvalidOperand(format!(
+                            "`{}` is not an instruction",
+                            data.directive()
+                        )),
+                        statement.span,
+                    ))
+                }
+            };
+
+        Ok(MachineCodeInstruction::new(
+            self.int_to_bytes(self.current_label),
+            instr,
+            operand1_type,
+            operand1,
+            operand2_type,
+            operand2,
+        ))
+    }
+
     pub fn register_to_bytes(&self, reg: Register) -> [u8; 4] {
         let mut list: [u8; 4] = Default::default();
         list.copy_from_slice(&(reg as u32).to_le_bytes());
@@ -371,24 +863,148 @@ impl MachineCodeCompiler {
         list
     }
 
-    pub fn memory_to_bytes(&self, mem: MemoryFetching) -> (u8, [u8; 4]) {
+    pub fn label_to_bytes(&self, label: &str, span: Span) -> Result<(u8, [u8; 4]), CompileError> {
+        match self.labels.get(label) {
+            Some(addr) => Ok((LABEL, self.int_to_bytes(*addr))),
+            None => Err(CompileError::new(
+                CompileErrorKind::UnknownLabel(label.to_string()),
+                span,
+            )),
+        }
+    }
+
+    /// Like `label_to_bytes`, for the target of a `call` or a jump.
+    pub fn code_label_to_bytes(
+        &self,
+        label: &str,
+        span: Span,
+    ) -> Result<(u8, [u8; 4]), CompileError> {
+        if self.data_labels.contains(label) {
+            return Err(CompileError::new(
+                CompileErrorKind::InvalidOperand(format!("Cannot jump to data label `{}`", label)),
+                span,
+            ));
+        }
+        self.label_to_bytes(label, span)
+    }
+
+    pub fn memory_to_bytes(
+        &self,
+        mem: MemoryFetching,
+        span: Span,
+    ) -> Result<(u8, [u8; 4]), CompileError> {
         match mem {
-            MemoryFetching::Register(reg) => (REG, self.register_to_bytes(reg)),
-            MemoryFetching::Addr(mem) => (MEM, self.int_to_bytes(mem as u32)),
+            MemoryFetching::Register(reg) => Ok((REG, self.register_to_bytes(reg))),
+            MemoryFetching::Addr(mem) => match u32::try_from(mem) {
+                Ok(mem) => Ok((MEM, self.int_to_bytes(mem))),
+                Err(_) => Err(CompileError::new(
+                    CompileErrorKind::InvalidOperand(format!(
+                        "Address #{} does not fit in 32 bits",
+                        mem
+                    )),
+                    span,
+                )),
+            },
+        }
+    }
+
+    pub fn expr_to_bytes(&self, expr: Expr, span: Span) -> Result<(u8, [u8; 4]), CompileError> {
+        match self.evaluator().eval(&expr, span)? {
+            Expr::Int(int) => Ok((INT, self.int_to_bytes(int as u32))),
+            Expr::Label(label) => self.label_to_bytes(&label, span),
+            Expr::Memory(mem) => self.memory_to_bytes(mem, span),
+            _ => unreachable!("folded by Evaluator::eval"),
         }
     }
 
-    pub fn expr_to_bytes(&self, expr: Expr) -> (u8, [u8; 4]) {
-        match expr {
-            Expr::Int(int) => (INT, self.int_to_bytes(int as u32)),
-            Expr::Label(label) => (LABEL, self.int_to_bytes(*self.labels.get(&label).unwrap())),
-            Expr::Memory(mem) => self.memory_to_bytes(mem),
+    /// Encodes `data`, the directive of `statement`. An invalid operand is
+    /// reported at its own span.
+    pub fn data_to_bytes(
+        &self,
+        data: &Data,
+        statement: &Statement,
+    ) -> Result<Vec<u8>, CompileError> {
+        let span = |i| statement.operand_span(i);
+        let invalid = |message: String, span: Span| {
+            CompileError::new(CompileErrorKind::InvalidOperand(message), span)
+        };
+        match data {
+            Data::Byte(values) => values
+                .iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    let value = self.data_value(x, span(i))?;
+                    match value {
+                        -128..=255 => Ok(value as u8),
+                        _ => Err(invalid(
+                            format!("Byte value {} is out of range", value),
+                            span(i),
+                        )),
+                    }
+                })
+                .collect(),
+            Data::Word(values) => {
+                let mut bytes = vec![];
+                for (i, value) in values.iter().enumerate() {
+                    bytes.extend(self.data_value(value, span(i))?.to_le_bytes());
+                }
+                Ok(bytes)
+            }
+            Data::String(string) => {
+                let mut bytes = string.as_bytes().to_vec();
+                bytes.push(0);
+                Ok(bytes)
+            }
+            Data::Zero(count) => {
+                match Evaluator::new(&self.constants, None).value(count, span(0))? {
+                    count if count >= 0 => Ok(vec![0; count as usize]),
+                    count => Err(invalid(
+                        format!("Expected a byte count, found `{}`", count),
+                        span(0),
+                    )),
+                }
+            }
         }
     }
+
+    /// Number of bytes `data_to_bytes` gives for `statements`, not counting
+    /// invalid directives. The `.zero` counts cannot depend on label addresses,
+    /// which are not known yet.
+    fn data_size(&self, statements: &[Statement]) -> u64 {
+        let evaluator = Evaluator::new(&self.constants, None);
+        statements
+            .iter()
+            .map(|x| match &x.command {
+                Command::Data(Data::Byte(values)) => values.len() as u64,
+                Command::Data(Data::Word(values)) => 4 * values.len() as u64,
+                Command::Data(Data::String(string)) => string.len() as u64 + 1,
+                Command::Data(Data::Zero(count)) => evaluator
+                    .value(count, x.span)
+                    .ok()
+                    .and_then(|x| u64::try_from(x).ok())
+                    .unwrap_or(0),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Value of a `.byte` or `.word` operand: an integer or a label address.
+    fn data_value(&self, expr: &Expr, span: Span) -> Result<i32, CompileError> {
+        self.evaluator().value(expr, span)
+    }
+}
+
+/// Number of instructions a label compiles to.
+fn code_len(label: &Label) -> u32 {
+    label
+        .program
+        .iter()
+        .filter(|x| !x.command.is_data())
+        .count() as u32
 }
 
 fn add_zero(string: String, size: usize) -> String {
-    let added_zeros = if size.clone() < string.len() {
+    let added_zeros = if size < string.len() {
         "".to_string()
     } else {
         "0".repeat(size - string.len())
@@ -400,23 +1016,31 @@ fn add_zero(string: String, size: usize) -> String {
 mod tests {
     use super::*;
 
+    fn label(name: &str, commands: Vec<Command>) -> Label {
+        Label::new(
+            name.to_string(),
+            commands.into_iter().map(Statement::from).collect(),
+        )
+    }
+
     #[test]
     fn test_binary_string() {
         let instr = MachineCodeInstruction::new(
-            0,
+            [0; 4],
             MOV,
             REG,
             [0x10, 0x32, 0x45, 0x0],
             INT,
             [0x1, 0x0, 0x0, 0x0],
         );
-        assert_eq!(instr.binary_string(), "0000 0010001 0001 00010000 00110010 01000101 00000000 0010 00000001 00000000 00000000 00000000");
+        assert_eq!(instr.binary_string(&AssemblerOptions::new()), "000000000000000000000000000000000010001000100010000001100100100010100000000001000000001000000000000000000000000");
+        assert_eq!(instr.binary_string(&AssemblerOptions::new().with_mode(Mode::Debug)), "00000000 00000000 00000000 00000000 0010001 0001 00010000 00110010 01000101 00000000 0010 00000001 00000000 00000000 00000000");
     }
 
     #[test]
     fn test_hex_string() {
         let instr = MachineCodeInstruction::new(
-            0,
+            [0; 4],
             MOV,
             REG,
             [0xC8, 0xB, 0xA2, 0xB3],
@@ -424,8 +1048,12 @@ mod tests {
             [0x1, 0x0, 0x0, 0x0],
         );
         assert_eq!(
-            instr.hex_string(),
-            "0000 0011 0001 00c8 000b 00a2 00b3 0002 0001 0000 0000 0000"
+            instr.hex_string(&AssemblerOptions::new()),
+            "00000000000000000011000100c8000b00a200b300020001000000000000"
+        );
+        assert_eq!(
+            instr.hex_string(&AssemblerOptions::new().with_mode(Mode::Debug)),
+            "0000 0000 0000 0000 0011 0001 00c8 000b 00a2 00b3 0002 0001 0000 0000 0000"
         );
     }
 
@@ -433,7 +1061,7 @@ mod tests {
     fn test_fmt_bin() {
         let instrs = MachineCode::new(vec![
             MachineCodeInstruction::new(
-                0,
+                [0; 4],
                 MOV,
                 REG,
                 [0x1, 0x0, 0x0, 0x0],
@@ -441,7 +1069,7 @@ mod tests {
                 [0x2, 0x0, 0x0, 0x0],
             ),
             MachineCodeInstruction::new(
-                0,
+                [0; 4],
                 ADD,
                 REG,
                 [0x3, 0x0, 0x0, 0x0],
@@ -450,14 +1078,15 @@ mod tests {
             ),
         ]);
 
-        assert_eq!(format!("{:b}", instrs), "0000 0010001 0001 00000001 00000000 00000000 00000000 0010 00000010 00000000 00000000 00000000\n0000 0010010 0001 00000011 00000000 00000000 00000000 0010 00001001 00000000 00000000 00000000\n");
+        assert_eq!(format!("{:b}", instrs), "000000000000000000000000000000000010001000100000001000000000000000000000000001000000010000000000000000000000000\n000000000000000000000000000000000010010000100000011000000000000000000000000001000001001000000000000000000000000\n");
+        assert_eq!(format!("{:#b}", instrs), "00000000 00000000 00000000 00000000 0010001 0001 00000001 00000000 00000000 00000000 0010 00000010 00000000 00000000 00000000\n00000000 00000000 00000000 00000000 0010010 0001 00000011 00000000 00000000 00000000 0010 00001001 00000000 00000000 00000000\n");
     }
 
     #[test]
     fn test_fmt_hex() {
         let instrs = MachineCode::new(vec![
             MachineCodeInstruction::new(
-                0,
+                [0; 4],
                 MOV,
                 REG,
                 [0x1, 0x0, 0x0, 0x0],
@@ -465,7 +1094,7 @@ mod tests {
                 [0x2, 0x0, 0x0, 0x0],
             ),
             MachineCodeInstruction::new(
-                0,
+                [0; 4],
                 MOV,
                 REG,
                 [0x3, 0x0, 0x0, 0x0],
@@ -474,13 +1103,14 @@ mod tests {
             ),
         ]);
 
-        assert_eq!(format!("{:x}", instrs), "0000 0011 0001 0001 0000 0000 0000 0002 0002 0000 0000 0000\n0000 0011 0001 0003 0000 0000 0000 0002 0009 0000 0000 0000\n");
+        assert_eq!(format!("{:x}", instrs), "000000000000000000110001000100000000000000020002000000000000\n000000000000000000110001000300000000000000020009000000000000\n");
+        assert_eq!(format!("{:#x}", instrs), "0000 0000 0000 0000 0011 0001 0001 0000 0000 0000 0002 0002 0000 0000 0000\n0000 0000 0000 0000 0011 0001 0003 0000 0000 0000 0002 0009 0000 0000 0000\n");
     }
 
     #[test]
     fn test_to_bytecode() {
         let instr = MachineCodeInstruction::new(
-            0,
+            [0; 4],
             MOV,
             REG,
             [0x1, 0x0, 0x0, 0x0],
@@ -490,46 +1120,319 @@ mod tests {
 
         assert_eq!(
             instr.to_bytecode(),
-            vec![0x0, MOV, REG, 0x1, 0x0, 0x0, 0x0, INT, 0x1, 0x0, 0x0, 0x0]
+            vec![0x0, 0x0, 0x0, 0x0, MOV, REG, 0x1, 0x0, 0x0, 0x0, INT, 0x1, 0x0, 0x0, 0x0]
+        );
+    }
+
+    #[test]
+    fn test_from_bytecode() {
+        for opcode in OPCODES {
+            let instr = MachineCodeInstruction::new(
+                [0x3, 0x0, 0x0, 0x0],
+                opcode,
+                MEM,
+                [0x10, 0x27, 0x0, 0x0],
+                LABEL,
+                [0x2, 0x0, 0x0, 0x0],
+            );
+            assert_eq!(
+                MachineCodeInstruction::from_bytecode(&instr.to_bytecode()),
+                Ok(instr)
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_bytecode_errors() {
+        let mut bytecode = MachineCodeInstruction::end_marker().to_bytecode();
+        assert_eq!(
+            MachineCodeInstruction::from_bytecode(&bytecode[1..])
+                .unwrap_err()
+                .offset,
+            0
+        );
+
+        bytecode[10] = 0x9;
+        assert_eq!(
+            MachineCodeInstruction::from_bytecode(&bytecode)
+                .unwrap_err()
+                .offset,
+            10
+        );
+
+        let mut code =
+            MachineCode::new(vec![MachineCodeInstruction::end_marker(); 2]).to_bytecode();
+        code[INSTRUCTION_SIZE + 4] = 0x7f;
+        assert_eq!(
+            MachineCode::from_bytecode(&code).unwrap_err(),
+            DecodeError::new("Unknown opcode 0x7f".to_string(), INSTRUCTION_SIZE + 4)
+        );
+        assert_eq!(
+            MachineCode::from_bytecode(&code[..20]).unwrap_err().offset,
+            INSTRUCTION_SIZE
         );
     }
 
     #[test]
     fn test_mov() {
-        let mut compiler = MachineCodeCompiler::new(Program::new(vec![Command::Mov(Mov(
-            MemoryFetching::Register(Register::R1),
-            Expr::Int(1),
-        ))]));
-        let machine_code = compiler.compile();
+        let mut compiler = MachineCodeCompiler::new(Program::new(vec![label(
+            "main",
+            vec![Command::Mov(Mov(
+                MemoryFetching::Register(Register::R1),
+                Expr::Int(1),
+            ))],
+        )]));
+        let machine_code = compiler.compile().unwrap();
         assert_eq!(
             machine_code.code,
-            vec![MachineCodeInstruction::new(
-                0,
-                MOV,
-                REG,
-                [0x1, 0x0, 0x0, 0x0],
-                INT,
-                [0x1, 0x0, 0x0, 0x0]
-            )]
+            vec![
+                MachineCodeInstruction::new(
+                    [0x1, 0x0, 0x0, 0x0],
+                    MOV,
+                    REG,
+                    [0x1, 0x0, 0x0, 0x0],
+                    INT,
+                    [0x1, 0x0, 0x0, 0x0]
+                ),
+                MachineCodeInstruction::new(
+                    [0x1, 0x0, 0x0, 0x0],
+                    NOP,
+                    VOID,
+                    Default::default(),
+                    VOID,
+                    Default::default()
+                )
+            ]
         );
     }
 
     #[test]
     fn test_pop() {
-        let mut compiler = MachineCodeCompiler::new(Program::new(vec![Command::Pop(Pop(
-            MemoryFetching::Register(Register::R1),
-        ))]));
-        let machine_code = compiler.compile();
+        let mut compiler = MachineCodeCompiler::new(Program::new(vec![label(
+            "main",
+            vec![Command::Pop(Pop(MemoryFetching::Register(Register::R1)))],
+        )]));
+        let machine_code = compiler.compile().unwrap();
         assert_eq!(
-            machine_code.code,
-            vec![MachineCodeInstruction::new(
-                0,
+            machine_code.code[0],
+            MachineCodeInstruction::new(
+                [0x1, 0x0, 0x0, 0x0],
                 POP,
                 REG,
                 [0x1, 0x0, 0x0, 0x0],
                 VOID,
                 Default::default()
-            )]
+            )
+        );
+    }
+
+    #[test]
+    fn test_emit_bytes() {
+        let instrs = MachineCode::new(vec![MachineCodeInstruction::new(
+            [0x1, 0x0, 0x0, 0x0],
+            MOV,
+            REG,
+            [0x1, 0x0, 0x0, 0x0],
+            INT,
+            [0x7, 0x0, 0x0, 0x0],
+        )]);
+
+        let options = AssemblerOptions::new();
+        assert_eq!(
+            instrs.emit(&options.clone().with_format(OutputFormat::Bytes)),
+            instrs.code[0].to_bytecode()
+        );
+        assert_eq!(
+            instrs.emit(&options.clone().with_format(OutputFormat::Binary)),
+            format!("{:b}", instrs).into_bytes()
+        );
+        assert_eq!(
+            instrs.emit(
+                &options
+                    .with_format(OutputFormat::Hex)
+                    .with_mode(Mode::Debug)
+            ),
+            format!("{:#x}", instrs).into_bytes()
+        );
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let source = "main:\n    call $missing\n    jl $gone\nmain:\n    mov #4294967296, $main\n";
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let errors = MachineCodeCompiler::new(program).compile().unwrap_err();
+
+        let reported = errors
+            .iter()
+            .map(|x| (x.message(), x.span.extract_from_str(source)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reported,
+            [
+                ("Label `main` is defined more than once".to_string(), "main"),
+                ("Unknown label `missing`".to_string(), "$missing"),
+                ("Unknown label `gone`".to_string(), "$gone"),
+                (
+                    "Address #4294967296 does not fit in 32 bits".to_string(),
+                    "#4294967296"
+                ),
+            ]
+        );
+        assert_eq!(
+            errors[0].kind,
+            CompileErrorKind::DuplicateLabel("main".to_string(), Span::new(0, 4))
+        );
+        assert_eq!(errors[0].span, Span::new(37, 41));
+    }
+
+    #[test]
+    fn test_data() {
+        let source = "main:\n    mov r1, $bytes\nbytes:\n    .byte 1, 2\n    .string \"abc\"\n";
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let mut compiler = MachineCodeCompiler::new(program);
+        let machine_code = compiler.compile().unwrap();
+
+        assert_eq!(compiler.labels()["bytes"], DATA_START);
+        assert_eq!(machine_code.instructions().len(), 2);
+        let statements = compiler
+            .statements()
+            .iter()
+            .map(|(addr, x)| (*addr, x.span.extract_from_str(source)))
+            .collect::<Vec<_>>();
+        assert_eq!(statements, [(1, "mov r1, $bytes")]);
+        assert_eq!(machine_code.data(), [1, 2, b'a', b'b', b'c', 0]);
+        assert_eq!(
+            machine_code.records()[3],
+            MachineCodeInstruction::new(
+                [0x4, 0x80, 0x0, 0x0],
+                DATA,
+                INT,
+                [b'c', 0x0, 0x0, 0x0],
+                INT,
+                [0x2, 0x0, 0x0, 0x0],
+            )
+        );
+
+        let bytecode = machine_code.to_bytecode();
+        let decoded = MachineCode::from_bytecode(&bytecode).unwrap();
+        assert_eq!(decoded.instructions(), machine_code.instructions());
+        assert_eq!(decoded.data(), machine_code.data());
+
+        let mut reordered = bytecode[INSTRUCTION_SIZE * 3..].to_vec();
+        reordered.extend(&bytecode[..INSTRUCTION_SIZE * 3]);
+        let err = MachineCode::from_bytecode(&reordered).unwrap_err();
+        assert_eq!(err.message, "Data record at #32772, expected #32768");
+    }
+
+    #[test]
+    fn test_data_errors() {
+        let source = "main:\n    jmp $msg\n    .byte 1\nmsg:\n    .byte 256, $main\n    .zero r1\n";
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let errors = MachineCodeCompiler::new(program).compile().unwrap_err();
+
+        let reported = errors
+            .iter()
+            .map(|x| (x.message(), x.span.extract_from_str(source)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reported,
+            [
+                (
+                    "Label `main` mixes data directives and instructions".to_string(),
+                    "main"
+                ),
+                ("Cannot jump to data label `msg`".to_string(), "$msg"),
+                ("Byte value 256 is out of range".to_string(), "256"),
+                ("`r1` is not a constant".to_string(), "r1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_limit() {
+        let source = "buf:\n    .zero 2147483647\n    .zero 2147483647\n    .zero 2147483647\n";
+        let errors = MachineCodeCompiler::new(crate::parse_string(source).unwrap())
+            .compile()
+            .unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code(), ErrorCode::InvalidOperand);
+        assert_eq!(
+            errors[0].message(),
+            format!("The data segment is larger than {} bytes", DATA_LIMIT)
+        );
+        assert_eq!(errors[0].span.start(), source.find(".zero").unwrap() + 21);
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = r#"
+        .equ BUF_SIZE, 4
+        .equ LEN, $end - $start
+        start:
+            mov r1, BUF_SIZE * 4 + 1
+            mov r2, LEN
+            mov r3, $buf
+        end:
+            nop
+        buf:
+            .zero BUF_SIZE * 2
+        after:
+            .word BUF_SIZE, $after - $buf
+        "#;
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let mut compiler = MachineCodeCompiler::new(program);
+        let machine_code = compiler.compile().unwrap();
+
+        let operands = machine_code.instructions()[..3]
+            .iter()
+            .map(|x| (x.operand2_type, u32::from_le_bytes(x.operand2)))
+            .collect::<Vec<_>>();
+        assert_eq!(operands, [(INT, 17), (INT, 3), (LABEL, DATA_START)]);
+        assert_eq!(compiler.labels()["after"], DATA_START + 8);
+        assert_eq!(machine_code.data()[8..], [4, 0, 0, 0, 8, 0, 0, 0]);
+
+        let source =
+            ".equ A, B\n.equ B, A + 1\n.equ A, 2\nmain:\n    mov r1, A\n    div r1, 1 / (A - A)\n";
+        let mut lexer = crate::lexer::Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let program = crate::parser::Parser::new(lexer.get_tokens())
+            .parse()
+            .unwrap();
+        let errors = MachineCodeCompiler::new(program).compile().unwrap_err();
+        let reported = errors
+            .iter()
+            .map(|x| (x.message(), x.span.extract_from_str(source)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reported,
+            [
+                ("Constant `A` is defined more than once".to_string(), "A"),
+                (
+                    "Constant `A` is defined in terms of itself".to_string(),
+                    "A"
+                ),
+                (
+                    "Constant `A` is defined in terms of itself".to_string(),
+                    "1 / (A - A)"
+                ),
+            ]
         );
     }
 }
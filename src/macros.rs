@@ -0,0 +1,157 @@
+//! `.macro` definitions, expanded by the parser into the tokens of each call
+//! before they are parsed.
+//!
+//! ```text
+//! .macro swap a, b
+//!     mov r15, a
+//!     mov a, b
+//!     mov b, r15
+//! .endm
+//! ```
+//!
+//! A parameter used in the body is replaced by the tokens of its argument.
+//! Labels defined in the body are local: every expansion renames them, and
+//! the `$label` references to them in the body, so that a macro using a label
+//! can be called more than once.
+
+use crate::lexer::{Token, TokenKind};
+use crate::span::Span;
+use std::collections::HashSet;
+
+#[derive(Clone, Debug)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    /// Tokens between the `.macro` line and `.endm`, newlines included.
+    pub body: Vec<Token>,
+    /// Span of the macro name where it is defined.
+    pub span: Span,
+}
+
+impl Macro {
+    pub fn new(name: String, params: Vec<String>, body: Vec<Token>) -> Self {
+        Self {
+            name,
+            params,
+            body,
+            span: Span::default(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Name given to the local label `label` in expansion number `id`.
+    pub fn local_label_name(&self, label: &str, id: usize) -> String {
+        format!("__{}_{}_{}", self.name, id, label)
+    }
+
+    /// Labels defined in the body: an identifier followed by `:` at the
+    /// start of a line.
+    pub fn local_labels(&self) -> HashSet<String> {
+        self.body
+            .iter()
+            .enumerate()
+            .filter(|(i, token)| {
+                token.token_kind == TokenKind::Ident
+                    && (*i == 0 || self.body[i - 1].token_kind == TokenKind::Newline)
+                    && self
+                        .body
+                        .get(i + 1)
+                        .is_some_and(|x| x.token_kind == TokenKind::Colon)
+            })
+            .map(|(_, token)| token.lexeme.clone())
+            .collect()
+    }
+
+    /// The body for a call with `args`, one token list per parameter.
+    ///
+    /// The tokens keep their spans in the definition, and an argument takes the
+    /// span of the parameter it replaces; every token gets `call_site`.
+    pub fn expand(&self, args: &[Vec<Token>], call_site: Span, id: usize) -> Vec<Token> {
+        let locals = self.local_labels();
+        let mut tokens = vec![];
+
+        for (i, token) in self.body.iter().enumerate() {
+            let mut token = token.clone();
+            token.call_site = Some(call_site);
+            if token.token_kind != TokenKind::Ident {
+                tokens.push(token);
+                continue;
+            }
+
+            let is_label = (i > 0 && self.body[i - 1].token_kind == TokenKind::Dollar)
+                || self
+                    .body
+                    .get(i + 1)
+                    .is_some_and(|x| x.token_kind == TokenKind::Colon);
+            if is_label && locals.contains(&token.lexeme) {
+                token.lexeme = self.local_label_name(&token.lexeme, id);
+                tokens.push(token);
+            } else if let Some(param) = self.params.iter().position(|x| *x == token.lexeme) {
+                tokens.extend(args[param].iter().map(|arg| Token {
+                    span: token.span,
+                    call_site: Some(call_site),
+                    ..arg.clone()
+                }));
+            } else {
+                tokens.push(token);
+            }
+        }
+
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn lex(source: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        lexer.get_tokens()
+    }
+
+    #[test]
+    fn test_expand() {
+        let body = lex("again:\n    add x, 1\n    jmp $again\n    jmp $out\n");
+        let mac = Macro::new("inc".to_string(), vec!["x".to_string()], body.clone());
+        assert_eq!(mac.local_labels(), HashSet::from(["again".to_string()]));
+
+        let call_site = Span::new(40, 48);
+        let expanded = mac.expand(&[lex("#8")], call_site, 3);
+        let lexemes = expanded
+            .iter()
+            .map(|x| x.lexeme.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lexemes,
+            [
+                "__inc_3_again",
+                ":",
+                "\n",
+                "add",
+                "#",
+                "8",
+                ",",
+                "1",
+                "\n",
+                "jmp",
+                "$",
+                "__inc_3_again",
+                "\n",
+                "jmp",
+                "$",
+                "out",
+                "\n"
+            ]
+        );
+        assert!(expanded.iter().all(|x| x.call_site == Some(call_site)));
+        assert_eq!(expanded[4].span, body[4].span);
+        assert_eq!(expanded[5].span, body[4].span);
+    }
+}
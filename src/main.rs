@@ -1,62 +1,545 @@
+use popper_asm::ast::{Program, Register};
+use popper_asm::diagnostic::{Diagnostic, Renderer, Severity};
+use popper_asm::emulator::Emulator;
+use popper_asm::error_code::ErrorCode;
+use popper_asm::formatter;
+use popper_asm::include::Loader;
 use popper_asm::lexer::Lexer;
-use popper_asm::machine_code::MachineCodeCompiler;
-use popper_asm::parser::Parser;
-use popper_asm::MODE;
+use popper_asm::lint::{lint, Level, Lint, LintLevels};
+use popper_asm::listing::listing;
+use popper_asm::machine_code::{MachineCode, MachineCodeCompiler};
+use popper_asm::map;
+use popper_asm::parser::Error;
+use popper_asm::source_map::SourceMap;
+use popper_asm::{disassemble_bytes, parse_file, AssemblerOptions, Mode, OutputFormat};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-fn main() {
-    if MODE.is_debug() {
-        debug();
-    } else if MODE.is_release() {
-        cli();
+const USAGE: &str = "\
+Usage: popper_asm <command> [options] <input>
+
+Options for commands that read PopperASM source:
+    -I <dir>             Also look for .include files in <dir> (repeatable)
+    --color <when>       Colour diagnostics: auto (default), always or never
+    --diagnostic-format <format>
+                         human (default), or json for one JSON object per line
+
+Options for commands that assemble (assemble, run, check):
+    --allow <lint>       Do not check <lint>
+    --warn <lint>        Report <lint> as a warning (the default)
+    --deny <lint>        Report <lint> as an error
+                         Lints: unused-label, unreachable-code, self-move,
+                         division-by-zero, or all of them with `all`
+
+Commands:
+    assemble <input>     Assemble a .popasm file, or a program saved by dump-ast
+        -o <output>          Output file (default: <input> with a .bin extension)
+        --format <format>    bytes (default), bin or hex
+        --debug              Space out the fields in the bin and hex formats
+        --listing <file>     Also write a listing: each source line with the
+                             address and bytes of its instructions, then the
+                             address of every label
+        --map <file>         Also write the address, size and kind of every
+                             label; as JSON if <file> ends in .json
+    disasm <input>       Disassemble a binary back to PopperASM source
+        -o <output>          Output file (default: stdout)
+    run <input>          Run a .popasm or .json file, or an assembled binary, on the emulator
+        --max-steps <n>      Stop after <n> instructions (default: 1000000)
+    check <input>        Report diagnostics without writing any output
+    fmt <input>          Print the program in canonical form
+        --check              Print nothing; fail if <input> is not formatted
+    dump-tokens <input>  Print the tokens produced by the lexer
+    dump-ast <input>     Print the parsed program as JSON
+    explain <code>       Describe an error code, such as E0001, with an example
+    help                 Print this message";
+
+/// Instructions `run` executes before giving up, unless `--max-steps` says
+/// otherwise, so that a program that loops forever still ends.
+const DEFAULT_MAX_STEPS: usize = 1_000_000;
+
+enum CliError {
+    /// Bad command line; the usage is printed.
+    Usage(String),
+    /// Anything else that stops a command, already described by the message.
+    Failed(String),
+    /// Diagnostics were already reported.
+    Diagnostics(usize),
+    /// Diagnostics were already reported as JSON, which nothing else may
+    /// be mixed with.
+    Reported,
+}
+
+type CliResult = Result<(), CliError>;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match cli(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(message)) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            ExitCode::from(2)
+        }
+        Err(CliError::Failed(message)) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+        Err(CliError::Diagnostics(count)) => {
+            eprintln!("{} error(s) found", count);
+            ExitCode::FAILURE
+        }
+        Err(CliError::Reported) => ExitCode::FAILURE,
     }
 }
 
-fn debug() {
-    let source = r#"
+fn cli(args: &[String]) -> CliResult {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), Args::parse(rest)?),
+        None => return Err(CliError::Usage("no command given".to_string())),
+    };
 
-    main:
-        mov r1, 7
-        mov r2, 3
-        mov r3, 5
-        add r1, r2
-    "#;
+    match command {
+        "assemble" => assemble(rest),
+        "disasm" => disasm(rest),
+        "run" => run(rest),
+        "check" => check(rest),
+        "fmt" => fmt(rest),
+        "dump-tokens" => dump_tokens(rest),
+        "dump-ast" => dump_ast(rest),
+        "explain" => explain(rest),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        e => Err(CliError::Usage(format!("unknown command `{}`", e))),
+    }
+}
+
+/// Flags of every command that reads PopperASM source.
+const SOURCE_FLAGS: [&str; 3] = ["-I", "--color", "--diagnostic-format"];
+
+/// Flags of every command that assembles.
+const LINT_FLAGS: [&str; 3] = ["--allow", "--warn", "--deny"];
+
+/// Command line after the subcommand: one input and `--flag [value]` pairs.
+struct Args {
+    input: Option<String>,
+    flags: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    const VALUE_FLAGS: [&'static str; 11] = [
+        "-o",
+        "--format",
+        "--max-steps",
+        "-I",
+        "--color",
+        "--diagnostic-format",
+        "--allow",
+        "--warn",
+        "--deny",
+        "--listing",
+        "--map",
+    ];
+    const SWITCHES: [&'static str; 2] = ["--debug", "--check"];
 
-    let mut lexer = Lexer::new(source);
-    lexer.scan_tokens();
-    let mut parser = Parser::new(lexer.get_tokens());
-    match parser.parse() {
-        Ok(out) => {
-            let mut machine_code_compiler = MachineCodeCompiler::new(out);
+    fn parse(args: &[String]) -> Result<Self, CliError> {
+        let mut input = None;
+        let mut flags = vec![];
+        let mut args = args.iter();
 
-            let m = machine_code_compiler.compile();
-            println!("Binary:\n{:b}", m);
+        while let Some(arg) = args.next() {
+            if Self::VALUE_FLAGS.contains(&arg.as_str()) {
+                let value = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage(format!("expected a value after `{}`", arg)))?;
+                flags.push((arg.clone(), Some(value.clone())));
+            } else if Self::SWITCHES.contains(&arg.as_str()) {
+                flags.push((arg.clone(), None));
+            } else if arg.starts_with('-') {
+                return Err(CliError::Usage(format!("unknown option `{}`", arg)));
+            } else if input.is_none() {
+                input = Some(arg.clone());
+            } else {
+                return Err(CliError::Usage(format!("unexpected argument `{}`", arg)));
+            }
         }
-        Err(e) => {
-            e.report(source);
+
+        Ok(Self { input, flags })
+    }
+
+    /// Fails on the first flag that is not in `flags`, as it does not apply
+    /// to `command`.
+    fn accept(&self, command: &str, flags: &[&[&str]]) -> CliResult {
+        let applies = |name: &str| flags.iter().any(|x| x.contains(&name));
+        match self.flags.iter().find(|(name, _)| !applies(name)) {
+            Some((name, _)) => Err(CliError::Usage(format!(
+                "`{}` does not apply to `{}`",
+                name, command
+            ))),
+            None => Ok(()),
         }
+    }
+
+    fn input(&self) -> Result<&str, CliError> {
+        self.input
+            .as_deref()
+            .ok_or_else(|| CliError::Usage("expected <input>".to_string()))
+    }
+
+    fn value(&self, flag: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(name, _)| name == flag)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Every value given to a repeatable flag, in order.
+    fn values(&self, flag: &str) -> Vec<&str> {
+        self.flags
+            .iter()
+            .filter(|(name, _)| name == flag)
+            .filter_map(|(_, value)| value.as_deref())
+            .collect()
+    }
+
+    fn switch(&self, flag: &str) -> bool {
+        self.flags.iter().any(|(name, _)| name == flag)
+    }
+
+    fn options(&self) -> Result<AssemblerOptions, CliError> {
+        let mut options = AssemblerOptions::new();
+        if let Some(format) = self.value("--format") {
+            options = options.with_format(format.parse::<OutputFormat>().map_err(CliError::Usage)?);
+        }
+        if self.switch("--debug") {
+            options = options.with_mode(Mode::Debug);
+        }
+        for path in self.values("-I") {
+            options = options.with_include_path(PathBuf::from(path));
+        }
+        Ok(options.with_lints(self.lints()?))
+    }
+
+    /// Lint levels from `--allow`, `--warn` and `--deny`, applied in order.
+    /// `all` stands for every lint.
+    fn lints(&self) -> Result<LintLevels, CliError> {
+        let mut lints = LintLevels::new();
+        for (flag, name) in &self.flags {
+            let level = match flag.as_str() {
+                "--allow" => Level::Allow,
+                "--warn" => Level::Warn,
+                "--deny" => Level::Deny,
+                _ => continue,
+            };
+            let name = name.as_deref().unwrap_or_default();
+            lints = match (name, Lint::from_name(name)) {
+                ("all", _) => lints.with_all(level),
+                (_, Some(lint)) => lints.with_level(lint, level),
+                (_, None) => {
+                    let names = Lint::ALL.map(|x| x.name()).join(", ");
+                    return Err(CliError::Usage(format!(
+                        "unknown lint `{}` (expected all, {})",
+                        name, names
+                    )));
+                }
+            };
+        }
+        Ok(lints)
+    }
+
+    fn reporter(&self) -> Result<Reporter, CliError> {
+        let color = match self.value("--color").unwrap_or("auto") {
+            "always" => true,
+            "never" => false,
+            "auto" => std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            e => {
+                return Err(CliError::Usage(format!(
+                    "unknown color mode `{}` (expected auto, always or never)",
+                    e
+                )))
+            }
+        };
+        let json = match self.value("--diagnostic-format").unwrap_or("human") {
+            "human" => false,
+            "json" => true,
+            e => {
+                return Err(CliError::Usage(format!(
+                    "unknown diagnostic format `{}` (expected human or json)",
+                    e
+                )))
+            }
+        };
+        Ok(Reporter {
+            renderer: Renderer::new().with_color(color),
+            json,
+        })
+    }
+}
+
+fn read_source(path: &str) -> Result<String, CliError> {
+    std::fs::read_to_string(path)
+        .map_err(|e| CliError::Failed(format!("cannot read `{}`: {}", path, e)))
+}
+
+fn write_output(path: &Path, content: &[u8]) -> CliResult {
+    std::fs::write(path, content)
+        .map_err(|e| CliError::Failed(format!("cannot write `{}`: {}", path.display(), e)))
+}
+
+fn is_json(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|x| x == "json")
+}
+
+/// Parses `source` with its includes, or loads it as a JSON program when
+/// `path` ends in `.json`. The files read are kept in `loader`.
+fn load_program(
+    reporter: &Reporter,
+    loader: &mut Loader,
+    path: &str,
+    source: &str,
+) -> Result<Program, CliError> {
+    if is_json(path) {
+        Program::from_json(source)
+            .map_err(|e| CliError::Failed(format!("cannot load `{}`: {}", path, e)))
+    } else {
+        parse_file(loader, Path::new(path), source.to_string())
+            .map_err(|e| reporter.errors(&e, loader.sources()))
+    }
+}
+
+fn load_machine_code(
+    reporter: &Reporter,
+    path: &str,
+    source: &str,
+    options: &AssemblerOptions,
+) -> Result<MachineCode, CliError> {
+    let (compiler, _) = load_compiler(reporter, path, source, options)?;
+    Ok(compiler.machine_code)
+}
+
+/// Lints and compiles the program in `source`, reporting every diagnostic.
+/// Returns the compiler, which holds the machine code and label addresses,
+/// and the files that were read.
+fn load_compiler(
+    reporter: &Reporter,
+    path: &str,
+    source: &str,
+    options: &AssemblerOptions,
+) -> Result<(MachineCodeCompiler, SourceMap), CliError> {
+    let mut loader = Loader::new(options.include_paths.clone());
+    let program = load_program(reporter, &mut loader, path, source)?;
+    // the spans of a JSON program point into the source it was dumped
+    // from, which is not at hand, so its diagnostics go without a snippet
+    let sources = if is_json(path) {
+        SourceMap::new()
+    } else {
+        loader.into_sources()
     };
+
+    let mut diagnostics = lint(&program, &options.lints);
+    let mut compiler = MachineCodeCompiler::with_options(program, options.clone());
+    if let Err(errors) = compiler.compile() {
+        diagnostics.extend(
+            errors
+                .into_iter()
+                .map(|x| Diagnostic::from(&Error::from(x))),
+        );
+    }
+    reporter.report(&diagnostics, &sources)?;
+    Ok((compiler, sources))
 }
 
-fn cli() {
-    let args: Vec<String> = std::env::args().collect();
-    let input = args.get(1).expect("Expected <input>");
-    let output = args.get(2).expect("Expected <output>");
-    let content_input = std::fs::read_to_string(input).expect("Dont find the file");
+/// Prints diagnostics the way the command line asked for.
+struct Reporter {
+    renderer: Renderer,
+    /// Print one JSON object per line instead of rendering for people.
+    json: bool,
+}
 
-    let mut lexer = Lexer::new(&content_input);
-    lexer.scan_tokens();
-    let mut parser = Parser::new(lexer.get_tokens());
+impl Reporter {
+    fn errors(&self, errors: &[Error], sources: &SourceMap) -> CliError {
+        for e in errors {
+            self.print(&Diagnostic::from(e), sources);
+        }
+        self.failure(errors.len())
+    }
 
-    match parser.parse() {
-        Ok(out) => {
-            let mut machine_code_compiler = MachineCodeCompiler::new(out);
+    /// Prints warnings and errors, failing if there is any error.
+    fn report(&self, diagnostics: &[Diagnostic], sources: &SourceMap) -> CliResult {
+        for diagnostic in diagnostics {
+            self.print(diagnostic, sources);
+        }
+        match diagnostics
+            .iter()
+            .filter(|x| x.severity == Severity::Error)
+            .count()
+        {
+            0 => Ok(()),
+            count => Err(self.failure(count)),
+        }
+    }
 
-            let m = machine_code_compiler.compile();
+    fn print(&self, diagnostic: &Diagnostic, sources: &SourceMap) {
+        if self.json {
+            eprintln!("{}", diagnostic.to_json(sources));
+        } else {
+            eprint!("{}", self.renderer.render(diagnostic, sources));
+        }
+    }
 
-            std::fs::write(output, format!("{:b}", m)).expect("Unable to write file");
+    fn failure(&self, count: usize) -> CliError {
+        match self.json {
+            true => CliError::Reported,
+            false => CliError::Diagnostics(count),
         }
-        Err(e) => {
-            e.report(&content_input);
+    }
+}
+
+fn assemble(args: Args) -> CliResult {
+    args.accept(
+        "assemble",
+        &[
+            &SOURCE_FLAGS,
+            &LINT_FLAGS,
+            &["-o", "--format", "--debug", "--listing", "--map"],
+        ],
+    )?;
+    let input = args.input()?;
+    let options = args.options()?;
+    let output = match args.value("-o") {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(input).with_extension("bin"),
+    };
+    let source = read_source(input)?;
+
+    let (compiler, sources) = load_compiler(&args.reporter()?, input, &source, &options)?;
+    write_output(&output, &compiler.machine_code.emit(&options))?;
+    if let Some(path) = args.value("--listing") {
+        write_output(Path::new(path), listing(&compiler, &sources).as_bytes())?;
+    }
+    if let Some(path) = args.value("--map") {
+        let map = match is_json(path) {
+            true => format!("{}\n", map::json(compiler.symbols())),
+            false => map::text(compiler.symbols()),
+        };
+        write_output(Path::new(path), map.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn disasm(args: Args) -> CliResult {
+    args.accept("disasm", &[&["-o"]])?;
+    let input = args.input()?;
+    let bytes = std::fs::read(input)
+        .map_err(|e| CliError::Failed(format!("cannot read `{}`: {}", input, e)))?;
+
+    let source = disassemble_bytes(&bytes)
+        .map_err(|e| CliError::Failed(format!("cannot disassemble `{}` {}", input, e)))?;
+    match args.value("-o") {
+        Some(output) => write_output(Path::new(output), source.as_bytes()),
+        None => {
+            print!("{}", source);
+            Ok(())
         }
+    }
+}
+
+fn run(args: Args) -> CliResult {
+    args.accept("run", &[&SOURCE_FLAGS, &LINT_FLAGS, &["--max-steps"]])?;
+    let input = args.input()?;
+    let is_source = Path::new(input).extension().is_some_and(|x| x == "popasm");
+    let machine_code = if is_source || is_json(input) {
+        let source = read_source(input)?;
+        load_machine_code(&args.reporter()?, input, &source, &args.options()?)?
+    } else {
+        let bytes = std::fs::read(input)
+            .map_err(|e| CliError::Failed(format!("cannot read `{}`: {}", input, e)))?;
+        MachineCode::from_bytecode(&bytes)
+            .map_err(|e| CliError::Failed(format!("cannot load `{}` {}", input, e)))?
+    };
+
+    let mut emulator = Emulator::new(machine_code);
+    let max_steps = match args.value("--max-steps") {
+        Some(max_steps) => max_steps
+            .parse::<usize>()
+            .map_err(|_| CliError::Usage(format!("invalid step count `{}`", max_steps)))?,
+        None => DEFAULT_MAX_STEPS,
+    };
+    let result = emulator.run_with_limit(max_steps);
+
+    for (i, value) in emulator.registers.iter().enumerate() {
+        println!("{:>4} = {}", Register::from(i as u8 + 1).to_string(), value);
+    }
+    println!("steps = {}", emulator.steps);
+    result.map_err(|e| CliError::Failed(format!("emulator stopped {}", e)))
+}
+
+fn check(args: Args) -> CliResult {
+    args.accept("check", &[&SOURCE_FLAGS, &LINT_FLAGS])?;
+    let input = args.input()?;
+    let source = read_source(input)?;
+    load_machine_code(&args.reporter()?, input, &source, &args.options()?)?;
+    Ok(())
+}
+
+fn fmt(args: Args) -> CliResult {
+    args.accept("fmt", &[&SOURCE_FLAGS, &["--check"]])?;
+    let input = args.input()?;
+    let source = read_source(input)?;
+    let mut loader = Loader::new(args.options()?.include_paths);
+    let program = load_program(&args.reporter()?, &mut loader, input, &source)?;
+    // a JSON program has no comments or layout to keep
+    let formatted = match is_json(input) {
+        true => program.to_string(),
+        false => formatter::format(&program, &source),
     };
+
+    if !args.switch("--check") {
+        print!("{}", formatted);
+        Ok(())
+    } else if formatted == source {
+        Ok(())
+    } else {
+        Err(CliError::Failed(format!("`{}` is not formatted", input)))
+    }
+}
+
+fn dump_tokens(args: Args) -> CliResult {
+    args.accept("dump-tokens", &[&["--color", "--diagnostic-format"]])?;
+    let input = args.input()?;
+    let source = read_source(input)?;
+    let mut lexer = Lexer::new(&source);
+    let result = lexer.scan_tokens();
+
+    for token in lexer.get_tokens() {
+        println!("{:?} {:?} {:?}", token.span, token.token_kind, token.lexeme);
+    }
+    let sources = SourceMap::new().with_file(Path::new(input), source.clone());
+    let reporter = args.reporter()?;
+    result.map_err(|e| reporter.errors(&e, &sources))
+}
+
+fn dump_ast(args: Args) -> CliResult {
+    args.accept("dump-ast", &[&SOURCE_FLAGS])?;
+    let input = args.input()?;
+    let source = read_source(input)?;
+    let mut loader = Loader::new(args.options()?.include_paths);
+    let program = load_program(&args.reporter()?, &mut loader, input, &source)?;
+    println!("{}", program.to_json());
+    Ok(())
+}
+
+fn explain(args: Args) -> CliResult {
+    args.accept("explain", &[])?;
+    let code = args
+        .input
+        .as_deref()
+        .ok_or_else(|| CliError::Usage("expected an error code".to_string()))?;
+    let code = ErrorCode::from_code(code)
+        .ok_or_else(|| CliError::Failed(format!("unknown error code `{}`", code)))?;
+    println!("{}: {}\n\n{}", code, code.title(), code.explanation());
+    Ok(())
 }
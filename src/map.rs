@@ -0,0 +1,74 @@
+//! Map files: where every label ended up.
+//!
+//! Both formats list the [`Symbol`]s of `MachineCodeCompiler::symbols` in
+//! the order the labels are defined. [`text`] writes one line per label:
+//!
+//! ```text
+//! address   size  kind  name
+//! 00000001     2  code  main
+//! 00008000     3  data  msg
+//! ```
+//!
+//! and [`json`] writes an array of `{"name", "address", "size", "kind"}`
+//! objects. The size of a code label counts instructions, and that of a data
+//! label counts bytes.
+
+use crate::machine_code::Symbol;
+
+pub fn text(symbols: &[Symbol]) -> String {
+    let mut out = "address   size  kind  name\n".to_string();
+    for symbol in symbols {
+        out += &format!(
+            "{:08x}  {:>4}  {}  {}\n",
+            symbol.address, symbol.size, symbol.kind, symbol.name
+        );
+    }
+    out
+}
+
+pub fn json(symbols: &[Symbol]) -> String {
+    serde_json::to_string_pretty(symbols).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine_code::{MachineCodeCompiler, SymbolKind, DATA_START};
+    use crate::parse_string;
+
+    #[test]
+    fn test_map() {
+        let source = "\
+main:
+    mov r1, $msg
+    call $print
+print:
+    ret
+msg:
+    .string \"hi\"
+table:
+    .word $main, $print
+";
+        let mut compiler = MachineCodeCompiler::new(parse_string(source).unwrap());
+        compiler.compile().unwrap();
+        let symbols = compiler.symbols();
+        assert_eq!(symbols[3].address, DATA_START + 3);
+        assert_eq!(symbols[3].kind, SymbolKind::Data);
+
+        assert_eq!(
+            text(symbols),
+            "\
+address   size  kind  name
+00000001     2  code  main
+00000003     1  code  print
+00008000     3  data  msg
+00008003     8  data  table
+"
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&json(symbols)).unwrap();
+        assert_eq!(
+            parsed[2],
+            serde_json::json!({"name": "msg", "address": 32768, "size": 3, "kind": "data"})
+        );
+    }
+}
@@ -1,26 +1,52 @@
 use crate::ast::*;
+use crate::diagnostic::Renderer;
+use crate::error_code::ErrorCode;
 use crate::lexer::{Token, TokenKind};
+use crate::macros::Macro;
+use crate::source_map::SourceMap;
 use crate::span::Span;
+use std::collections::HashMap;
+
+pub(crate) const MNEMONICS: [&str; 12] = [
+    "mov", "add", "sub", "mul", "div", "pop", "call", "allow", "ret", "nop", "jmp", "cmp",
+];
+
+/// Whether `name` is an instruction or register, which a macro or its
+/// parameters cannot be named after.
+fn is_reserved(name: &str) -> bool {
+    MNEMONICS.contains(&name)
+        || Condition::from_mnemonic(name).is_some()
+        || Register::from_name(name).is_some()
+}
 
 #[derive(Clone, Debug)]
 pub struct Error {
+    pub code: ErrorCode,
     pub message: String,
     pub span: Span,
+    /// See `Statement::call_site`.
+    pub call_site: Option<Span>,
 }
 
 impl Error {
-    pub fn new(message: String, span: Span) -> Self {
-        Self { message, span }
+    pub fn new(code: ErrorCode, message: String, span: Span) -> Self {
+        Self {
+            code,
+            message,
+            span,
+            call_site: None,
+        }
     }
 
-    pub fn report(&self, source: &str) {
-        let extract = self.span.extract_from_str(source);
-        let marker = self.span.make_marker(source);
-        let line = source.lines().nth(self.span.find_line(source) - 1).unwrap();
+    pub fn with_call_site(mut self, call_site: Option<Span>) -> Self {
+        self.call_site = call_site;
+        self
+    }
 
-        println!("Error:[{:?} {} `{:?}`", self.span, self.message, extract);
-        println!("Source: {}", line);
-        println!("{}", marker);
+    /// Prints the error, with the `file:line:col` and source lines of its
+    /// span and of its macro call site, looked up in `sources`.
+    pub fn report(&self, sources: &SourceMap) {
+        eprint!("{}", Renderer::new().render(&self.into(), sources));
     }
 }
 
@@ -29,6 +55,13 @@ pub struct Parser {
     pub tokens: Vec<Token>,
     pub current: usize,
     pub errors: Vec<Error>,
+    pub constants: Vec<Constant>,
+    pub macros: HashMap<String, Macro>,
+    /// Macros being expanded, with the index of the token after their expansion.
+    expanding: Vec<(String, usize)>,
+    expansions: usize,
+    /// Spans of the operands of the command being parsed.
+    operands: Vec<Span>,
 }
 
 impl Parser {
@@ -37,21 +70,50 @@ impl Parser {
             tokens,
             current: 0,
             errors: vec![],
+            constants: vec![],
+            macros: HashMap::new(),
+            expanding: vec![],
+            expansions: 0,
+            operands: vec![],
         }
     }
 
-    pub fn parse(&mut self) -> Result<Program, Error> {
-        let mut cmds = vec![];
+    /// Parses the whole program. On failure, every error found is returned,
+    /// in source order: after an error the parser skips to the next line and
+    /// carries on.
+    pub fn parse(&mut self) -> Result<Program, Vec<Error>> {
+        let mut labels = vec![];
 
+        self.ignore_newlines();
         while !self.is_at_end() {
-            let cmd = self.parse_label()?;
-            cmds.push(cmd);
+            if self.at_constant() || self.at_macro() || self.at_include() || self.at_macro_call() {
+                self.directive_line();
+                self.ignore_newlines();
+                continue;
+            }
+            match self.parse_label() {
+                Ok(label) => labels.push(label),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.synchronize();
+                    // still check the commands that follow the broken label
+                    let _ = self.parse_commands();
+                }
+            }
+            self.ignore_newlines();
         }
 
-        Ok(Program::new(cmds))
+        if self.errors.is_empty() {
+            Ok(Program::new(labels).with_constants(self.constants.clone()))
+        } else {
+            Err(self.errors.clone())
+        }
     }
 
     fn parse_command(&mut self) -> Result<Command, Error> {
+        if self.check() == TokenKind::Directive {
+            return self.parse_directive();
+        }
         let command = self.expect(TokenKind::Ident)?;
         let command = match command.lexeme.as_str() {
             "mov" => self.parse_mov(),
@@ -62,61 +124,135 @@ impl Parser {
             "pop" => self.parse_pop(),
             "call" => self.parse_call(),
             "allow" => self.parse_allow(),
-            _ => Err(Error::new("unexpected command".to_string(), command.span)),
+            "ret" => Ok(Command::Ret(Ret)),
+            "nop" => Ok(Command::Nop(Nop)),
+            "jmp" => self.parse_jump(),
+            "cmp" => self.parse_cmp(),
+            name => match Condition::from_mnemonic(name) {
+                Some(cond) => self.parse_cond_jump(cond),
+                None => Err(Error::new(
+                    ErrorCode::UnknownInstruction,
+                    "unexpected command".to_string(),
+                    command.span,
+                )),
+            },
         };
         command
     }
 
+    fn parse_directive(&mut self) -> Result<Command, Error> {
+        let directive = self.expect(TokenKind::Directive)?;
+        let data = match directive.lexeme.as_str() {
+            ".byte" => Data::Byte(self.parse_expr_list()?),
+            ".word" => Data::Word(self.parse_expr_list()?),
+            ".string" => Data::String(self.operand(Self::parse_string)?),
+            ".zero" => Data::Zero(self.operand(Self::parse_expr)?),
+            name => {
+                return Err(Error::new(
+                    ErrorCode::UnknownDirective,
+                    format!("Unknown directive `{}`", name),
+                    directive.span,
+                ))
+            }
+        };
+        Ok(Command::Data(data))
+    }
+
+    /// One or more comma separated expressions.
+    fn parse_expr_list(&mut self) -> Result<Vec<Expr>, Error> {
+        let mut exprs = vec![self.operand(Self::parse_expr)?];
+        while self.check() == TokenKind::Comma {
+            self.advance();
+            exprs.push(self.operand(Self::parse_expr)?);
+        }
+        Ok(exprs)
+    }
+
+    fn parse_string(&mut self) -> Result<String, Error> {
+        let string = self.expect(TokenKind::String)?;
+        let quoted = &string.lexeme[1..string.lexeme.len() - 1];
+        unescape(quoted).map_err(|e| {
+            Error::new(
+                ErrorCode::UnknownEscape,
+                format!("Unknown escape `\\{}`", e),
+                string.span,
+            )
+        })
+    }
+
     fn parse_mov(&mut self) -> Result<Command, Error> {
-        let mem = self.parse_memory_fetching()?;
-        let _ = self.expect(TokenKind::Comma);
-        let expr = self.parse_expr()?;
+        let mem = self.operand(Self::parse_memory_fetching)?;
+        let _ = self.expect(TokenKind::Comma)?;
+        let expr = self.operand(Self::parse_expr)?;
         Ok(Command::Mov(Mov(mem, expr)))
     }
 
     fn parse_add(&mut self) -> Result<Command, Error> {
-        let register = self.parse_memory_fetching()?;
+        let register = self.operand(Self::parse_memory_fetching)?;
         let _ = self.expect(TokenKind::Comma)?;
-        let expr = self.parse_expr()?;
+        let expr = self.operand(Self::parse_expr)?;
 
         Ok(Command::Add(Add(register, expr)))
     }
 
     fn parse_sub(&mut self) -> Result<Command, Error> {
-        let register = self.parse_memory_fetching()?;
+        let register = self.operand(Self::parse_memory_fetching)?;
         let _ = self.expect(TokenKind::Comma)?;
-        let expr = self.parse_expr()?;
+        let expr = self.operand(Self::parse_expr)?;
         Ok(Command::Sub(Sub(register, expr)))
     }
 
     fn parse_mul(&mut self) -> Result<Command, Error> {
-        let register = self.parse_memory_fetching()?;
+        let register = self.operand(Self::parse_memory_fetching)?;
         Ok(Command::Mul(Mul(register)))
     }
 
     fn parse_div(&mut self) -> Result<Command, Error> {
-        let register = self.parse_memory_fetching()?;
+        let register = self.operand(Self::parse_memory_fetching)?;
         let _ = self.expect(TokenKind::Comma)?;
-        let expr = self.parse_expr()?;
+        let expr = self.operand(Self::parse_expr)?;
         Ok(Command::Div(Div(register, expr)))
     }
 
     fn parse_pop(&mut self) -> Result<Command, Error> {
-        let register = self.parse_memory_fetching()?;
+        let register = self.operand(Self::parse_memory_fetching)?;
         Ok(Command::Pop(Pop(register)))
     }
 
     fn parse_call(&mut self) -> Result<Command, Error> {
-        let label = self.parse_label_name()?;
+        let label = self.operand(Self::parse_label_name)?;
         if let Expr::Label(label) = label {
             return Ok(Command::Call(Call(label)));
         }
         unreachable!()
     }
 
+    fn parse_jump(&mut self) -> Result<Command, Error> {
+        let label = self.operand(Self::parse_label_name)?;
+        if let Expr::Label(label) = label {
+            return Ok(Command::Jump(Jump(label)));
+        }
+        unreachable!()
+    }
+
+    fn parse_cmp(&mut self) -> Result<Command, Error> {
+        let register = self.operand(Self::parse_memory_fetching)?;
+        let _ = self.expect(TokenKind::Comma)?;
+        let expr = self.operand(Self::parse_expr)?;
+        Ok(Command::Cmp(Cmp(register, expr)))
+    }
+
+    fn parse_cond_jump(&mut self, cond: Condition) -> Result<Command, Error> {
+        let label = self.operand(Self::parse_label_name)?;
+        if let Expr::Label(label) = label {
+            return Ok(Command::CondJump(CondJump(cond, label)));
+        }
+        unreachable!()
+    }
+
     fn parse_allow(&mut self) -> Result<Command, Error> {
-        let to = self.parse_expr()?;
-        let from = self.parse_expr()?;
+        let to = self.operand(Self::parse_expr)?;
+        let from = self.operand(Self::parse_expr)?;
         Ok(Command::Allow(Allow(to, from)))
     }
 
@@ -126,84 +262,306 @@ impl Parser {
             TokenKind::Ident => register,
             _ => {
                 return Err(Error::new(
+                    ErrorCode::UnexpectedToken,
                     format!("Expected ident, found {:?}", register.token_kind),
                     register.span,
                 ))
             }
         };
-        let register = match register.lexeme.as_str() {
-            "r1" => Register::R1,
-            "r2" => Register::R2,
-            "r3" => Register::R3,
-            "r4" => Register::R4,
-            "r5" => Register::R5,
-            "r6" => Register::R6,
-            "r7" => Register::R7,
-            "r8" => Register::R8,
-            "r9" => Register::R9,
-            "r10" => Register::R10,
-            "r11" => Register::R11,
-            "r12" => Register::R12,
-            "r13" => Register::R13,
-            "r14" => Register::R14,
-            "r15" => Register::R15,
-            "rbp" => Register::Rbp,
-            _ => {
-                return Err(Error::new(
-                    format!("Expected register, found {:?}", register.lexeme),
-                    register.span,
-                ))
-            }
-        };
-
-        Ok(register)
+        Register::from_name(&register.lexeme).ok_or_else(|| {
+            Error::new(
+                ErrorCode::UnknownRegister,
+                format!("Expected register, found {:?}", register.lexeme),
+                register.span,
+            )
+        })
     }
 
     fn parse_memory_fetching(&mut self) -> Result<MemoryFetching, Error> {
-        match self.peek().token_kind {
+        match self.check() {
             TokenKind::Ident => Ok(MemoryFetching::Register(self.parse_register()?)),
             TokenKind::Hashtag => Ok(MemoryFetching::Addr(self.parse_addr()?)),
-            e => Err(Error::new(
-                format!("Expected ident or hashtag, found {:?}", e),
-                self.peek().span,
-            )),
+            e => {
+                let token = self.advance();
+                Err(Error::new(
+                    ErrorCode::UnexpectedToken,
+                    format!("Expected ident or hashtag, found {:?}", e),
+                    token.span,
+                ))
+            }
         }
     }
 
     fn parse_label(&mut self) -> Result<Label, Error> {
         let ident = self.expect(TokenKind::Ident)?;
-        let _ = self.expect(TokenKind::Colon);
+        let _ = self.expect(TokenKind::Colon)?;
+        let instrs = self.parse_commands();
+
+        Ok(Label::new(ident.lexeme, instrs).with_span(ident.span))
+    }
+
+    /// Parses commands up to the next label, recording errors instead of stopping.
+    fn parse_commands(&mut self) -> Vec<Statement> {
         let mut instrs = vec![];
 
-        while !self.is_at_end() {
+        loop {
             self.ignore_newlines();
-            let command = self.parse_command()?;
-            instrs.push(command);
-            let mut cloned_parser = self.clone();
-
-            if cloned_parser.parse_label().is_ok() {
+            if self.is_at_end() || self.at_label() {
                 break;
             }
+            if self.at_constant() || self.at_macro() || self.at_include() || self.at_macro_call() {
+                self.directive_line();
+                continue;
+            }
+            let start = self.tokens[self.current].span;
+            let call_site = self.tokens[self.current].call_site;
+            self.operands.clear();
+            match self.parse_command().and_then(|command| {
+                let span = start.to(self.peek().span);
+                self.expect_line_end()?;
+                Ok(Statement::new(command, span)
+                    .with_operands(std::mem::take(&mut self.operands))
+                    .with_call_site(call_site))
+            }) {
+                Ok(statement) => instrs.push(statement),
+                Err(e) => {
+                    let call_site = e.call_site.or(call_site);
+                    self.errors.push(e.with_call_site(call_site));
+                    self.synchronize();
+                }
+            }
+        }
+
+        instrs
+    }
+
+    /// Handles a constant definition, macro definition or macro call, which
+    /// can appear inside or outside labels, recording its error if any.
+    /// `.include` lines are expanded by `include::Loader` before parsing, so
+    /// any left here are an error.
+    fn directive_line(&mut self) {
+        let call_site = self.tokens[self.current].call_site;
+        let result = if self.at_constant() {
+            self.parse_constant()
+                .map(|constant| self.constants.push(constant))
+        } else if self.at_macro() {
+            self.parse_macro().map(|mac| {
+                self.macros.insert(mac.name.clone(), mac);
+            })
+        } else if self.at_include() {
+            Err(Error::new(
+                ErrorCode::InvalidInclude,
+                "`.include` can only be used in a file assembled from disk".to_string(),
+                self.advance().span,
+            ))
+        } else {
+            self.expand_macro()
+        };
+
+        if let Err(e) = result {
+            let call_site = e.call_site.or(call_site);
+            self.errors.push(e.with_call_site(call_site));
+            self.synchronize();
+        }
+    }
+
+    /// Parses a `.macro name params ... .endm` definition. The body is only
+    /// checked when the macro is called.
+    fn parse_macro(&mut self) -> Result<Macro, Error> {
+        let directive = self.expect(TokenKind::Directive)?;
+        if matches!(self.check(), TokenKind::Newline | TokenKind::Eof) {
+            return Err(Error::new(
+                ErrorCode::InvalidMacro,
+                "Expected a macro name".to_string(),
+                directive.span,
+            ));
+        }
+        // read the whole definition first, so that an error in the header
+        // does not leave the body to be parsed as commands
+        let header_end = self.line_end(self.current);
+        let body_end = (header_end..self.tokens.len()).find(|i| {
+            self.tokens[*i].token_kind == TokenKind::Directive && self.tokens[*i].lexeme == ".endm"
+        });
+        let header = self.tokens[self.current..header_end].to_vec();
+        let body = match body_end {
+            Some(end) => self.tokens[header_end + 1..end].to_vec(),
+            None => {
+                self.current = self.tokens.len();
+                return Err(Error::new(
+                    ErrorCode::UnterminatedMacro,
+                    "Unterminated macro, expected `.endm`".to_string(),
+                    directive.span,
+                ));
+            }
+        };
+        self.current = body_end.unwrap() + 1;
+        self.expect_line_end()?;
+
+        if let Some(nested) = body.iter().find(|x| x.lexeme == ".macro") {
+            return Err(Error::new(
+                ErrorCode::InvalidMacro,
+                "Macros cannot be defined inside a macro".to_string(),
+                nested.span,
+            ));
+        }
+        let mut header = Parser::new(header);
+        let mac = header.parse_macro_header()?;
+        if self.macros.contains_key(&mac.name) {
+            return Err(Error::new(
+                ErrorCode::DuplicateMacro,
+                format!("Macro `{}` is defined more than once", mac.name),
+                mac.span,
+            ));
+        }
+        Ok(Macro { body, ..mac })
+    }
+
+    /// Parses `name param1, param2` after `.macro`.
+    fn parse_macro_header(&mut self) -> Result<Macro, Error> {
+        let name = self.expect(TokenKind::Ident)?;
+        if is_reserved(&name.lexeme) {
+            return Err(Error::new(
+                ErrorCode::InvalidMacro,
+                format!("Cannot name a macro after `{}`", name.lexeme),
+                name.span,
+            ));
+        }
+        let mut params: Vec<String> = vec![];
+        if !self.is_at_end() {
+            loop {
+                let param = self.expect(TokenKind::Ident)?;
+                if is_reserved(&param.lexeme) || params.contains(&param.lexeme) {
+                    return Err(Error::new(
+                        ErrorCode::InvalidMacro,
+                        format!("Invalid macro parameter `{}`", param.lexeme),
+                        param.span,
+                    ));
+                }
+                params.push(param.lexeme);
+                if self.is_at_end() {
+                    break;
+                }
+                let _ = self.expect(TokenKind::Comma)?;
+            }
         }
+        Ok(Macro::new(name.lexeme, params, vec![]).with_span(name.span))
+    }
+
+    /// Replaces the macro call on the current line by the macro body, which
+    /// is then parsed as if it had been written there.
+    fn expand_macro(&mut self) -> Result<(), Error> {
+        let start = self.current;
+        let name = self.advance();
+        let end = self.line_end(start);
+        let call_span = name.span.to(self.tokens[end - 1].span);
+        let call_site = name.call_site.unwrap_or(call_span);
+
+        let mut args = vec![];
+        if end > start + 1 {
+            let mut depth = 0;
+            let mut arg = vec![];
+            for token in &self.tokens[start + 1..end] {
+                match token.token_kind {
+                    TokenKind::Comma if depth == 0 => {
+                        args.push(std::mem::take(&mut arg));
+                        continue;
+                    }
+                    TokenKind::LeftParen => depth += 1,
+                    TokenKind::RightParen => depth -= 1,
+                    _ => (),
+                }
+                arg.push(token.clone());
+            }
+            args.push(arg);
+        }
+
+        let mac = &self.macros[&name.lexeme];
+        if args.len() != mac.params.len() || args.iter().any(|x| x.is_empty()) {
+            return Err(Error::new(
+                ErrorCode::MacroArguments,
+                format!(
+                    "Macro `{}` takes {} argument(s), found {}",
+                    mac.name,
+                    mac.params.len(),
+                    args.len()
+                ),
+                call_span,
+            ));
+        }
+
+        self.expanding
+            .retain(|(_, expansion_end)| *expansion_end > start);
+        if self.expanding.iter().any(|(x, _)| *x == mac.name) {
+            return Err(Error::new(
+                ErrorCode::RecursiveMacro,
+                format!("Macro `{}` calls itself", mac.name),
+                call_span,
+            ));
+        }
+
+        let expanded = mac.expand(&args, call_site, self.expansions);
+        let name = mac.name.clone();
+        self.expansions += 1;
+
+        let len = expanded.len();
+        self.tokens.splice(start..end, expanded);
+        for (_, expansion_end) in &mut self.expanding {
+            *expansion_end = *expansion_end + len - (end - start);
+        }
+        self.expanding.push((name, start + len));
+        self.current = start;
+        Ok(())
+    }
 
-        Ok(Label::new(ident.lexeme, instrs))
+    /// Index of the newline ending the line of token `index`, or the number
+    /// of tokens on the last line.
+    fn line_end(&self, index: usize) -> usize {
+        (index..self.tokens.len())
+            .find(|i| self.tokens[*i].token_kind == TokenKind::Newline)
+            .unwrap_or(self.tokens.len())
     }
 
-    fn parse_int(&mut self) -> Result<Expr, Error> {
+    fn parse_constant(&mut self) -> Result<Constant, Error> {
+        let _ = self.expect(TokenKind::Directive)?;
+        let name = self.expect(TokenKind::Ident)?;
+        if Register::from_name(&name.lexeme).is_some() {
+            return Err(Error::new(
+                ErrorCode::ReservedConstantName,
+                format!("Cannot name a constant after register `{}`", name.lexeme),
+                name.span,
+            ));
+        }
+        let _ = self.expect(TokenKind::Comma)?;
+        let value = self.parse_expr()?;
+        self.expect_line_end()?;
+        Ok(Constant::new(name.lexeme, value).with_span(name.span))
+    }
+
+    /// `negative` is set when the literal follows a unary `-`, which is
+    /// folded in so that `-2147483648` is accepted.
+    fn parse_int(&mut self, negative: bool) -> Result<Expr, Error> {
         let int = self.peek();
         let int = match int.token_kind {
             TokenKind::Int => int,
             _ => {
                 return Err(Error::new(
+                    ErrorCode::UnexpectedToken,
                     format!("Expected int, found {:?}", int.token_kind),
                     int.span,
                 ))
             }
         };
-        let int = match int.lexeme.parse::<i32>() {
-            Ok(int) => int,
-            Err(_) => {
+        let value = int
+            .lexeme
+            .parse::<i64>()
+            .ok()
+            .map(|x| if negative { -x } else { x })
+            .and_then(|x| i32::try_from(x).ok());
+        let int = match value {
+            Some(int) => int,
+            None => {
                 return Err(Error::new(
+                    ErrorCode::InvalidNumber,
                     format!("Expected int, found {:?}", int.lexeme),
                     int.span,
                 ))
@@ -212,20 +570,76 @@ impl Parser {
         Ok(Expr::Int(int))
     }
 
+    /// Parses an operand. `+` and `-` bind looser than `*`, `/` and `%`, and
+    /// operators of the same precedence group to the left.
     fn parse_expr(&mut self) -> Result<Expr, Error> {
-        let expr = self.advance();
-        let expr = match expr.token_kind {
-            TokenKind::Int => self.parse_int()?,
+        self.parse_binary(1)
+    }
+
+    fn parse_binary(&mut self, precedence: u8) -> Result<Expr, Error> {
+        let mut left = self.parse_unary()?;
+        while let Some(op) = self.binary_op().filter(|x| x.precedence() >= precedence) {
+            self.advance();
+            let right = self.parse_binary(op.precedence() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn binary_op(&self) -> Option<BinaryOp> {
+        match self.check() {
+            TokenKind::Plus => Some(BinaryOp::Add),
+            TokenKind::Minus => Some(BinaryOp::Sub),
+            TokenKind::Star => Some(BinaryOp::Mul),
+            TokenKind::Slash => Some(BinaryOp::Div),
+            TokenKind::Percent => Some(BinaryOp::Rem),
+            _ => None,
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, Error> {
+        if self.check() != TokenKind::Minus {
+            return self.parse_primary();
+        }
+        self.advance();
+        if self.check() == TokenKind::Int {
+            self.advance();
+            return self.parse_int(true);
+        }
+        Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, Error> {
+        let expr = match self.check() {
+            TokenKind::Int => {
+                self.advance();
+                self.parse_int(false)?
+            }
             TokenKind::Dollar => self.parse_label_name()?,
-            TokenKind::Hashtag | TokenKind::Ident => Expr::Memory(self.parse_memory_fetching()?),
+            TokenKind::Hashtag => Expr::Memory(self.parse_memory_fetching()?),
+            TokenKind::Ident => {
+                let ident = self.advance();
+                match Register::from_name(&ident.lexeme) {
+                    Some(register) => Expr::Memory(MemoryFetching::Register(register)),
+                    None => Expr::Const(ident.lexeme),
+                }
+            }
+            TokenKind::LeftParen => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                let _ = self.expect(TokenKind::RightParen)?;
+                expr
+            }
             _ => {
+                let expr = self.advance();
                 return Err(Error::new(
+                    ErrorCode::UnexpectedToken,
                     format!(
                         "Expected int, ident or hashtag, found {:?}",
                         expr.token_kind
                     ),
                     expr.span,
-                ))
+                ));
             }
         };
         Ok(expr)
@@ -233,12 +647,25 @@ impl Parser {
 
     fn parse_addr(&mut self) -> Result<usize, Error> {
         self.advance();
-        let n = self
-            .expect(TokenKind::Int)?
-            .lexeme
-            .parse::<usize>()
-            .unwrap();
-        Ok(n)
+        let addr = self.expect(TokenKind::Int)?;
+        addr.lexeme.parse::<usize>().map_err(|_| {
+            Error::new(
+                ErrorCode::InvalidNumber,
+                format!("Expected address, found {:?}", addr.lexeme),
+                addr.span,
+            )
+        })
+    }
+
+    /// Parses an operand with `parse`, recording the span of its tokens.
+    fn operand<T>(&mut self, parse: fn(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        // at the end of the input, `parse` reports the missing operand
+        let Some(start) = self.tokens.get(self.current).map(|x| x.span) else {
+            return parse(self);
+        };
+        let operand = parse(self)?;
+        self.operands.push(start.to(self.peek().span));
+        Ok(operand)
     }
 
     fn parse_label_name(&mut self) -> Result<Expr, Error> {
@@ -261,16 +688,73 @@ impl Parser {
         self.tokens[self.current - 1].clone()
     }
 
+    /// Kind of the next token, without consuming it.
+    fn check(&self) -> TokenKind {
+        self.tokens
+            .get(self.current)
+            .map_or(TokenKind::Eof, |token| token.token_kind)
+    }
+
+    /// Whether the next tokens are `ident :`, the start of a new label.
+    fn at_label(&self) -> bool {
+        self.check() == TokenKind::Ident
+            && self
+                .tokens
+                .get(self.current + 1)
+                .is_some_and(|token| token.token_kind == TokenKind::Colon)
+    }
+
+    fn at_macro(&self) -> bool {
+        self.tokens.get(self.current).is_some_and(|token| {
+            token.token_kind == TokenKind::Directive && token.lexeme == ".macro"
+        })
+    }
+
+    fn at_include(&self) -> bool {
+        self.tokens.get(self.current).is_some_and(|token| {
+            token.token_kind == TokenKind::Directive && token.lexeme == ".include"
+        })
+    }
+
+    /// Whether the next token is the name of a macro, and not a label definition.
+    fn at_macro_call(&self) -> bool {
+        self.check() == TokenKind::Ident
+            && !self.at_label()
+            && self.macros.contains_key(&self.tokens[self.current].lexeme)
+    }
+
+    /// Whether the next token starts a `.equ` or `.const` line.
+    fn at_constant(&self) -> bool {
+        self.tokens.get(self.current).is_some_and(|token| {
+            token.token_kind == TokenKind::Directive
+                && matches!(token.lexeme.as_str(), ".equ" | ".const")
+        })
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.tokens.len()
     }
 
     fn expect(&mut self, token_kind: TokenKind) -> Result<Token, Error> {
+        if self.is_at_end() {
+            return Err(Error::new(
+                ErrorCode::UnexpectedToken,
+                format!("Expected {:?}, found end of file", token_kind),
+                self.peek().span,
+            ));
+        }
         let token = self.advance();
         if token.token_kind == token_kind {
             Ok(token)
+        } else if token.token_kind == TokenKind::Newline {
+            Err(Error::new(
+                ErrorCode::UnexpectedToken,
+                format!("Expected {:?}, found end of line", token_kind),
+                token.span,
+            ))
         } else {
             Err(Error::new(
+                ErrorCode::UnexpectedToken,
                 format!(
                     "Expected {:?}, found {:?} `{}`",
                     token_kind, token.token_kind, token.lexeme
@@ -280,9 +764,396 @@ impl Parser {
         }
     }
 
+    fn expect_line_end(&mut self) -> Result<(), Error> {
+        match self.check() {
+            TokenKind::Eof => Ok(()),
+            TokenKind::Newline => {
+                self.advance();
+                Ok(())
+            }
+            e => {
+                let token = self.advance();
+                Err(Error::new(
+                    ErrorCode::UnexpectedToken,
+                    format!("Expected end of line, found {:?} `{}`", e, token.lexeme),
+                    token.span,
+                ))
+            }
+        }
+    }
+
     fn ignore_newlines(&mut self) {
-        while self.peek().token_kind == TokenKind::Newline {
+        while self.check() == TokenKind::Newline {
             let _ = self.advance();
         }
     }
+
+    /// Skips the rest of the line after an error, unless the error
+    /// already consumed the newline.
+    fn synchronize(&mut self) {
+        while !self.is_at_end() {
+            if self.current > 0 && self.peek().token_kind == TokenKind::Newline {
+                return;
+            }
+            self.current += 1;
+        }
+    }
+}
+
+/// Reverses `ast::quote_string`. On failure, returns the character after the
+/// bad `\\`.
+fn unescape(string: &str) -> Result<String, char> {
+    let mut unescaped = String::new();
+    let mut chars = string.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => unescaped.push('"'),
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('0') => unescaped.push('\0'),
+            e => return Err(e.unwrap_or(' ')),
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(source: &str) -> Result<Program, Vec<Error>> {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        Parser::new(lexer.get_tokens()).parse()
+    }
+
+    #[test]
+    fn test_ret_nop_jmp() {
+        let source = "main:\n    nop\n    jmp $end\nend:\n    ret\n";
+        let program = parse(source).unwrap();
+        assert_eq!(
+            program,
+            Program::new(vec![
+                Label::new(
+                    "main".to_string(),
+                    vec![
+                        Statement::new(Command::Nop(Nop), Span::new(10, 13)),
+                        Statement::new(Command::Jump(Jump("end".to_string())), Span::new(18, 26))
+                            .with_operands(vec![Span::new(22, 26)]),
+                    ]
+                )
+                .with_span(Span::new(0, 4)),
+                Label::new(
+                    "end".to_string(),
+                    vec![Statement::new(Command::Ret(Ret), Span::new(36, 39))]
+                )
+                .with_span(Span::new(27, 30)),
+            ])
+        );
+        assert_eq!(
+            program.labels[0].program[1].span.extract_from_str(source),
+            "jmp $end"
+        );
+    }
+
+    #[test]
+    fn test_cmp_cond_jump() {
+        let program = parse("loop:\n    cmp r1, 10\n    jl $loop\n    jge $loop\n").unwrap();
+        assert_eq!(
+            program.labels[0]
+                .program
+                .iter()
+                .map(|x| x.command.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Command::Cmp(Cmp(MemoryFetching::Register(Register::R1), Expr::Int(10))),
+                Command::CondJump(CondJump(Condition::Less, "loop".to_string())),
+                Command::CondJump(CondJump(Condition::GreaterEqual, "loop".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jmp_expects_label() {
+        let err = parse("main:\n    jmp end\n").unwrap_err();
+        assert_eq!(err[0].span, Span::new(14, 17));
+    }
+
+    #[test]
+    fn test_collect_errors() {
+        let source = r#"
+        main:
+            mov r1, 1
+            mvo r2, 2
+            add r1,
+            sub r1, 1 2
+        other:
+            pop rx
+            ret
+        "#;
+        let errors = parse(source).unwrap_err();
+        let extracts = errors
+            .iter()
+            .map(|x| x.span.extract_from_str(source))
+            .collect::<Vec<_>>();
+        assert_eq!(extracts, ["mvo", "\n", "2", "rx"]);
+    }
+
+    #[test]
+    fn test_recover_after_bad_label() {
+        let source = "main\n    mov r1, 1\n    mov r1 2\nend:\n    ret\n";
+        let errors = parse(source).unwrap_err();
+        let messages = errors
+            .iter()
+            .map(|x| x.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "Expected Colon, found end of line",
+                "Expected Comma, found Int `2`"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let source =
+            "msg: .string \"hi\\n\"\ntable:\n    .byte 1, 2, 255\n    .word $msg\n    .zero 4\n";
+        let program = parse(source).unwrap();
+        assert_eq!(
+            program.labels[0].program[0].command,
+            Command::Data(Data::String("hi\n".to_string()))
+        );
+        assert_eq!(
+            program.labels[1]
+                .program
+                .iter()
+                .map(|x| x.command.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Command::Data(Data::Byte(vec![Expr::Int(1), Expr::Int(2), Expr::Int(255)])),
+                Command::Data(Data::Word(vec![Expr::Label("msg".to_string())])),
+                Command::Data(Data::Zero(Expr::Int(4))),
+            ]
+        );
+        assert_eq!(
+            program.to_string(),
+            source
+                .replacen("msg: ", "msg:\n    ", 1)
+                .replacen("\ntable", "\n\ntable", 1)
+        );
+
+        let errors = parse(
+            "data:\n    .bytes 1\n    .byte\n    .string \"\\q\"\n    .include \"x.popasm\"\n",
+        )
+        .unwrap_err();
+        let messages = errors
+            .iter()
+            .map(|x| x.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "Unknown directive `.bytes`",
+                "Expected int, ident or hashtag, found Newline",
+                "Unknown escape `\\q`",
+                "`.include` can only be used in a file assembled from disk",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = ".equ BUF_SIZE, 16\nmain:\n    mov r1, BUF_SIZE * 4 + 1\n    .const LEN, $end - $main\n    mov r2, -(1 - 2) - -3 % (r1 + 1)\nend:\n";
+        let program = parse(source).unwrap();
+        assert_eq!(
+            program.constants,
+            vec![
+                Constant::new("BUF_SIZE".to_string(), Expr::Int(16)).with_span(Span::new(5, 13)),
+                Constant::new(
+                    "LEN".to_string(),
+                    Expr::Binary(
+                        BinaryOp::Sub,
+                        Box::new(Expr::Label("end".to_string())),
+                        Box::new(Expr::Label("main".to_string()))
+                    )
+                )
+                .with_span(Span::new(64, 67)),
+            ]
+        );
+        assert_eq!(
+            program.labels[0].program[0].command,
+            Command::Mov(Mov(
+                MemoryFetching::Register(Register::R1),
+                Expr::Binary(
+                    BinaryOp::Add,
+                    Box::new(Expr::Binary(
+                        BinaryOp::Mul,
+                        Box::new(Expr::Const("BUF_SIZE".to_string())),
+                        Box::new(Expr::Int(4))
+                    )),
+                    Box::new(Expr::Int(1))
+                )
+            ))
+        );
+        assert_eq!(
+            program.to_string(),
+            ".equ BUF_SIZE, 16\n.equ LEN, $end - $main\n\nmain:\n    mov r1, BUF_SIZE * 4 + 1\n    mov r2, -(1 - 2) - -3 % (r1 + 1)\n\nend:\n"
+        );
+        let text = program.to_string();
+        assert_eq!(parse(&text).unwrap().to_string(), text);
+
+        let errors = parse(".equ r1, 2\n.equ X 2\nmain:\n    mov r1, (1 + 2\n").unwrap_err();
+        let messages = errors
+            .iter()
+            .map(|x| x.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "Cannot name a constant after register `r1`",
+                "Expected Comma, found Int `2`",
+                "Expected RightParen, found end of line",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = r#"
+        .macro prologue size
+            sub rbp, size
+            mov #0, r1
+        .endm
+        .macro abs reg
+            cmp reg, 0
+            jge $done
+            mov r14, 0
+            sub r14, reg
+            mov reg, r14
+        done:
+        .endm
+        main:
+            prologue 4 * 2
+            abs r1
+            abs r2
+            ret
+        "#;
+        let program = parse(source).unwrap();
+        let names = program
+            .labels
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["main", "__abs_1_done", "__abs_2_done"]);
+        assert_eq!(
+            program.labels[0].program[0].command.to_string(),
+            "sub rbp, 4 * 2"
+        );
+        assert_eq!(
+            program.labels[1].program[1].command.to_string(),
+            "jge $__abs_2_done"
+        );
+
+        let cmp = &program.labels[1].program[0];
+        assert_eq!(cmp.span.extract_from_str(source), "cmp reg, 0");
+        assert_eq!(cmp.call_site.unwrap().extract_from_str(source), "abs r2");
+        assert_eq!(program.labels[2].program[0].call_site, None);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let source = r#"
+        .macro twice x
+            twice x
+        .endm
+        .macro mov a
+        .endm
+        .macro store a, b
+            mov #a, b
+        .endm
+        main:
+            twice 1
+            store 1
+            store r1, 2
+        .macro open
+        "#;
+        let errors = parse(source).unwrap_err();
+        let reported = errors
+            .iter()
+            .map(|x| {
+                (
+                    x.message.as_str(),
+                    x.span.extract_from_str(source),
+                    x.call_site.map(|x| x.extract_from_str(source)),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reported,
+            [
+                ("Cannot name a macro after `mov`", "mov", None),
+                ("Macro `twice` calls itself", "twice x", Some("twice 1")),
+                (
+                    "Macro `store` takes 2 argument(s), found 1",
+                    "store 1",
+                    None
+                ),
+                ("Expected Int, found Ident `r1`", "a", Some("store r1, 2")),
+                ("Unterminated macro, expected `.endm`", ".macro", None),
+            ]
+        );
+        assert_eq!(
+            errors.iter().map(|x| x.code).collect::<Vec<_>>(),
+            [
+                ErrorCode::InvalidMacro,
+                ErrorCode::RecursiveMacro,
+                ErrorCode::MacroArguments,
+                ErrorCode::UnexpectedToken,
+                ErrorCode::UnterminatedMacro,
+            ]
+        );
+
+        let source =
+            "main:\n    .macro jump_to target\n    jmp $target\n    .endm\n    jump_to nowhere\n";
+        let program = parse(source).unwrap();
+        let errors = crate::machine_code::MachineCodeCompiler::new(program)
+            .compile()
+            .unwrap_err();
+        assert_eq!(errors[0].span.extract_from_str(source), "$target");
+        assert_eq!(
+            errors[0].call_site.unwrap().extract_from_str(source),
+            "jump_to nowhere"
+        );
+    }
+
+    #[test]
+    fn test_one_command_per_line() {
+        let program = parse("main: mov r1, 1\n\n\n    ret").unwrap();
+        assert_eq!(program.labels[0].program.len(), 2);
+        assert!(parse("main:\n    nop nop\n").is_err());
+    }
+
+    #[test]
+    fn test_truncated_operand() {
+        for source in [
+            "main: mov",
+            "main:\n    call",
+            "main:\n    mov r1,",
+            "main:\n    .byte 1,",
+        ] {
+            let errors = parse(source).unwrap_err();
+            assert_eq!(errors.len(), 1, "{:?}", source);
+            assert_eq!(errors[0].code, ErrorCode::UnexpectedToken, "{:?}", source);
+        }
+        let errors = parse("main:\n    call").unwrap_err();
+        assert_eq!(errors[0].message, "Expected Dollar, found end of file");
+    }
 }
@@ -0,0 +1,170 @@
+//! The source files of one assembler run.
+//!
+//! A [`Span`] only holds byte offsets and the [`FileId`] of its file; the
+//! [`SourceMap`] turns it back into a path, a line and a column for
+//! diagnostics. Lines and columns start at 1, and columns count characters.
+
+use crate::span::{FileId, Span};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub source: String,
+    /// Byte offset of the start of every line.
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(path: PathBuf, source: String) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            path,
+            source,
+            line_starts,
+        }
+    }
+
+    /// Line and column of the byte at `offset`.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|x| *x <= offset) - 1;
+        let start = self.line_starts[line];
+        let col = self.source[start..offset.min(self.source.len())]
+            .chars()
+            .count();
+        (line + 1, col + 1)
+    }
+
+    /// Text of line `line`, without its newline.
+    pub fn line(&self, line: usize) -> &str {
+        &self.source[self.line_range(line)]
+    }
+
+    /// Byte range of line `line`, without its newline.
+    pub fn line_range(&self, line: usize) -> Range<usize> {
+        let start = self.line_starts[line - 1];
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(self.source.len(), |x| x - 1);
+        let end = start + self.source[start..end].trim_end_matches('\r').len();
+        start..end
+    }
+
+    /// Number of lines, not counting the empty one after a final newline.
+    pub fn line_count(&self) -> usize {
+        match self.source.ends_with('\n') {
+            true => self.line_starts.len() - 1,
+            false => self.line_starts.len(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A map holding only `source`, read from `path`, as file 0.
+    pub fn with_file(mut self, path: &Path, source: String) -> Self {
+        self.add(path.to_path_buf(), source);
+        self
+    }
+
+    pub fn add(&mut self, path: PathBuf, source: String) -> FileId {
+        self.files.push(SourceFile::new(path, source));
+        self.files.len() - 1
+    }
+
+    /// File `file`, or `None` if the map has no such file.
+    pub fn file(&self, file: FileId) -> Option<&SourceFile> {
+        self.files.get(file)
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// The file holding `span`, or `None` if the span does not lie within
+    /// one of the files, as for a program read back from JSON.
+    pub fn file_of(&self, span: Span) -> Option<&SourceFile> {
+        let file = self.file(span.file())?;
+        let fits = span.start() <= span.end()
+            && file.source.is_char_boundary(span.start())
+            && file.source.is_char_boundary(span.end());
+        fits.then_some(file)
+    }
+
+    /// Text covered by `span`.
+    pub fn extract(&self, span: Span) -> Option<&str> {
+        Some(span.extract_from_str(&self.file_of(span)?.source))
+    }
+
+    /// Line and column where `span` starts.
+    pub fn line_col(&self, span: Span) -> Option<(usize, usize)> {
+        Some(self.file_of(span)?.line_col(span.start()))
+    }
+
+    /// `path:line:col` of the start of `span`.
+    pub fn location(&self, span: Span) -> Option<String> {
+        let (line, col) = self.line_col(span)?;
+        let path = self.file_of(span)?.path.display();
+        Some(format!("{}:{}:{}", path, line, col))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        let mut sources =
+            SourceMap::new().with_file(Path::new("main.popasm"), "main:\n    mov r1, 1\n".into());
+        let lib = sources.add("lib/é.popasm".into(), "é: ret\r\nnext:".into());
+
+        assert_eq!(
+            sources.location(Span::new(0, 4)).unwrap(),
+            "main.popasm:1:1"
+        );
+        assert_eq!(
+            sources.location(Span::new(10, 13)).unwrap(),
+            "main.popasm:2:5"
+        );
+        assert_eq!(
+            sources.location(Span::new(20, 20)).unwrap(),
+            "main.popasm:3:1"
+        );
+        assert_eq!(sources.file(0).unwrap().line_count(), 2);
+        assert_eq!(
+            sources.location(Span::new(4, 7).with_file(lib)).unwrap(),
+            "lib/é.popasm:1:4"
+        );
+        assert_eq!(
+            sources.location(Span::new(9, 13).with_file(lib)).unwrap(),
+            "lib/é.popasm:2:1"
+        );
+        assert_eq!(
+            sources.extract(Span::new(9, 13).with_file(lib)),
+            Some("next")
+        );
+
+        let file = sources.file(lib).unwrap();
+        assert_eq!(file.line_count(), 2);
+        assert_eq!(file.line(1), "é: ret");
+        assert_eq!(file.line(2), "next:");
+
+        assert!(sources.file(3).is_none());
+        assert_eq!(sources.location(Span::new(0, 4).with_file(3)), None);
+        assert_eq!(sources.line_col(Span::new(0, 400)), None);
+        assert_eq!(sources.extract(Span::new(1, 3).with_file(lib)), None);
+    }
+}
@@ -1,61 +1,114 @@
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::ops::Range;
 
-#[derive(Copy, Clone, PartialEq)]
+/// Index of a source file, in the order the files were loaded. The file
+/// given to the assembler is 0, files pulled in by `.include` come after it.
+pub type FileId = usize;
+
+#[derive(Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct Span {
     start: usize,
     end: usize,
+    #[serde(default, skip_serializing_if = "is_main_file")]
+    file: FileId,
+}
+
+fn is_main_file(file: &FileId) -> bool {
+    *file == 0
 }
 
 impl Debug for Span {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[{}..{}]", self.start, self.end)
+        if self.file == 0 {
+            write!(f, "[{}..{}]", self.start, self.end)
+        } else {
+            write!(f, "[{}:{}..{}]", self.file, self.start, self.end)
+        }
     }
 }
 
 impl From<Range<usize>> for Span {
     fn from(value: Range<usize>) -> Self {
-        Self {
-            start: value.start,
-            end: value.end,
-        }
+        Self::new(value.start, value.end)
     }
 }
 
 impl Span {
     pub fn new(start: usize, end: usize) -> Self {
-        Self { start, end }
+        Self {
+            start,
+            end,
+            file: 0,
+        }
+    }
+
+    pub fn with_file(mut self, file: FileId) -> Self {
+        self.file = file;
+        self
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
     }
+
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    /// The smallest span covering both `self` and `other`, which must be in
+    /// the same file.
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end)).with_file(self.file)
+    }
+
     pub fn extract_from_str<'a>(&self, string: &'a str) -> &'a str {
         &string[self.start..self.end]
     }
 
+    /// A line of `^` under the part of `self` on its first line of `string`,
+    /// to be printed below that line. Tabs before the span are kept so that
+    /// the carets line up.
     pub fn make_marker(&self, string: &str) -> String {
-        let mut marker = String::new();
-        for _ in 0..self.start {
-            marker.push(' ');
-        }
-        for _ in self.start..self.end {
-            marker.push('^');
-        }
+        let line_start = string[..self.start].rfind('\n').map_or(0, |x| x + 1);
+        let line_end = string[self.start..]
+            .find('\n')
+            .map_or(string.len(), |x| self.start + x);
 
-        for _ in self.end..string.len() {
-            marker.push(' ');
-        }
+        let mut marker = string[line_start..self.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let carets = string[self.start..self.end.min(line_end)].chars().count();
+        marker.push_str(&"^".repeat(carets.max(1)));
         marker
     }
 
+    /// Line of `string`, counted from 1, where `self` starts.
     pub fn find_line(&self, string: &str) -> usize {
-        let mut line = 1;
-        for (i, c) in string.chars().enumerate() {
-            if i == self.start {
-                break;
-            }
-            if c == '\n' {
-                line += 1;
-            }
-        }
+        string[..self.start].matches('\n').count() + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_marker() {
+        let source = "main:\n\tmov r1, 1\n    jmp $main\n";
+        let span = Span::new(21, 30);
+        assert_eq!(span.extract_from_str(source), "jmp $main");
+        assert_eq!(span.find_line(source), 3);
+        assert_eq!(span.make_marker(source), "    ^^^^^^^^^");
 
-        line
+        assert_eq!(Span::new(7, 10).make_marker(source), "\t^^^");
+        assert_eq!(Span::new(0, 30).make_marker(source), "^^^^^");
+        assert_eq!(Span::new(5, 6).find_line(source), 1);
+        assert_eq!(Span::new(31, 31).make_marker(source), "^");
     }
 }
@@ -0,0 +1,283 @@
+//! Runs the `popper_asm` binary on files in a scratch directory, checking
+//! the output and exit code of every subcommand.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const PROGRAM: &str = "\
+main:
+    mov  r1, 7
+    call $double
+    ret
+
+double:
+    add r1, r1
+    ret
+";
+
+/// A fresh directory for the files of test `name`.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("popasm-cli-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs `popper_asm` with `args` in `dir`.
+fn popasm(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_popper_asm"))
+        .args(args)
+        .current_dir(dir)
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_assemble() {
+    let dir = scratch("assemble");
+    std::fs::write(dir.join("main.popasm"), PROGRAM).unwrap();
+
+    let output = popasm(
+        &dir,
+        &[
+            "assemble",
+            "main.popasm",
+            "--listing",
+            "main.lst",
+            "--map",
+            "main.map",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(std::fs::read(dir.join("main.bin")).unwrap().len() % 15, 0);
+    assert!(std::fs::read_to_string(dir.join("main.lst"))
+        .unwrap()
+        .contains("00000002  "));
+    assert!(std::fs::read_to_string(dir.join("main.map"))
+        .unwrap()
+        .contains("00000004     2  code  double"));
+
+    std::fs::write(dir.join("bad.popasm"), "main:\n    call $nope\n").unwrap();
+    let output = popasm(&dir, &["assemble", "bad.popasm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("error[E0018]: Unknown label `nope`"));
+    assert!(!dir.join("bad.bin").exists());
+}
+
+#[test]
+fn test_disasm() {
+    let dir = scratch("disasm");
+    std::fs::write(dir.join("main.popasm"), PROGRAM).unwrap();
+    assert!(popasm(&dir, &["assemble", "main.popasm"]).status.success());
+
+    let output = popasm(&dir, &["disasm", "main.bin"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).contains("call $label_4"));
+
+    std::fs::write(dir.join("bad.bin"), "abc").unwrap();
+    let output = popasm(&dir, &["disasm", "bad.bin"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("cannot disassemble `bad.bin`"));
+}
+
+#[test]
+fn test_run() {
+    let dir = scratch("run");
+    std::fs::write(dir.join("main.popasm"), PROGRAM).unwrap();
+
+    let output = popasm(&dir, &["run", "main.popasm"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).contains("  r1 = 14\n"));
+    assert!(stdout(&output).ends_with("steps = 5\n"));
+
+    let output = popasm(&dir, &["run", "main.popasm", "--max-steps", "2"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).ends_with("steps = 2\n"));
+
+    let output = popasm(&dir, &["run", "main.popasm", "--max-steps", "x"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).starts_with("error: invalid step count `x`"));
+}
+
+#[test]
+fn test_run_forever() {
+    let dir = scratch("run-forever");
+    std::fs::write(dir.join("loop.popasm"), "main:\n    jmp $main\n").unwrap();
+
+    let output = popasm(&dir, &["run", "loop.popasm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).ends_with("steps = 1000000\n"));
+    assert!(stderr(&output).contains("Step limit of 1000000 reached"));
+
+    let output = popasm(&dir, &["run", "loop.popasm", "--max-steps", "2000000"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Step limit of 2000000 reached"));
+}
+
+#[test]
+fn test_check() {
+    let dir = scratch("check");
+    std::fs::write(dir.join("main.popasm"), PROGRAM).unwrap();
+    std::fs::write(
+        dir.join("unused.popasm"),
+        "main:\n    ret\nspare:\n    ret\n",
+    )
+    .unwrap();
+
+    let output = popasm(&dir, &["check", "main.popasm"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stderr(&output), "");
+
+    let output = popasm(&dir, &["check", "unused.popasm"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stderr(&output).starts_with("warning"));
+
+    let output = popasm(&dir, &["check", "unused.popasm", "--deny", "all"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("error"));
+
+    let output = popasm(
+        &dir,
+        &[
+            "check",
+            "unused.popasm",
+            "--deny",
+            "all",
+            "--diagnostic-format",
+            "json",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let diagnostic: serde_json::Value = serde_json::from_str(stderr(&output).trim()).unwrap();
+    assert_eq!(diagnostic["severity"], "error");
+}
+
+#[test]
+fn test_fmt() {
+    let dir = scratch("fmt");
+    std::fs::write(dir.join("main.popasm"), PROGRAM).unwrap();
+    std::fs::write(dir.join("messy.popasm"), "main:\n  mov r1,7\n  ret\n").unwrap();
+
+    let output = popasm(&dir, &["fmt", "main.popasm"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), PROGRAM);
+    assert!(popasm(&dir, &["fmt", "main.popasm", "--check"])
+        .status
+        .success());
+
+    let output = popasm(&dir, &["fmt", "messy.popasm", "--check"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("`messy.popasm` is not formatted"));
+}
+
+#[test]
+fn test_dump() {
+    let dir = scratch("dump");
+    std::fs::write(dir.join("main.popasm"), PROGRAM).unwrap();
+    std::fs::write(dir.join("bad.popasm"), "main:\n    mov r1, @\n").unwrap();
+
+    let output = popasm(&dir, &["dump-tokens", "main.popasm"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Ident \"double\""));
+    let output = popasm(&dir, &["dump-tokens", "bad.popasm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("error[E0001]"));
+
+    let output = popasm(&dir, &["dump-ast", "main.popasm"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let program: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(program["labels"][1]["name"], "double");
+    let output = popasm(&dir, &["dump-ast", "bad.popasm"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_json_program() {
+    // spans of a dumped program point into files that are not at hand
+    let dir = scratch("json-program");
+    std::fs::write(
+        dir.join("main.popasm"),
+        "main:\n    call $lib\n.include \"lib.popasm\"\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("lib.popasm"), "lib:\n    mov r1, $nope\n    ret\n").unwrap();
+
+    let output = popasm(&dir, &["dump-ast", "main.popasm"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    std::fs::write(dir.join("main.json"), &output.stdout).unwrap();
+
+    let output = popasm(&dir, &["check", "main.json"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        "error[E0018]: Unknown label `nope`\n1 error(s) found\n"
+    );
+}
+
+#[test]
+fn test_explain() {
+    let dir = scratch("explain");
+
+    let output = popasm(&dir, &["explain", "E0001"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("E0001: "));
+
+    let output = popasm(&dir, &["explain", "E9999"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "error: unknown error code `E9999`\n");
+}
+
+#[test]
+fn test_usage() {
+    let dir = scratch("usage");
+    std::fs::write(dir.join("main.popasm"), PROGRAM).unwrap();
+
+    let output = popasm(&dir, &["help"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("Usage: popper_asm"));
+
+    for (args, message) in [
+        (&[][..], "error: no command given"),
+        (&["build"][..], "error: unknown command `build`"),
+        (&["check"][..], "error: expected <input>"),
+        (
+            &["check", "main.popasm", "--fast"][..],
+            "error: unknown option `--fast`",
+        ),
+        (
+            &["check", "main.popasm", "-o"][..],
+            "error: expected a value after `-o`",
+        ),
+        (
+            &["disasm", "main.bin", "--deny", "all"][..],
+            "error: `--deny` does not apply to `disasm`",
+        ),
+        (
+            &["fmt", "main.popasm", "--map", "x"][..],
+            "error: `--map` does not apply to `fmt`",
+        ),
+        (
+            &["check", "main.popasm", "-o", "x"][..],
+            "error: `-o` does not apply to `check`",
+        ),
+        (
+            &["explain", "E0001", "--debug"][..],
+            "error: `--debug` does not apply to `explain`",
+        ),
+    ] {
+        let output = popasm(&dir, args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(stderr(&output).starts_with(message), "{:?}", args);
+        assert!(stderr(&output).contains("Usage: popper_asm"), "{:?}", args);
+    }
+}